@@ -0,0 +1,20 @@
+//! Source of `exports.dll`, see `../README.md` for how to rebuild it.
+#![no_std]
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+#[no_mangle]
+pub extern "C" fn fixture_add(a: u32, b: u32) -> u32 {
+    a.wrapping_add(b)
+}
+
+#[no_mangle]
+pub extern "C" fn fixture_answer() -> u32 {
+    42
+}
+
+#[no_mangle]
+pub static fixture_data: [u8; 4] = *b"memf";
@@ -0,0 +1,185 @@
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+const PT_LOAD: u32 = 1;
+
+// offsets into the file header
+const E_IDENT_CLASS: usize = 4;
+const E_IDENT_DATA: usize = 5;
+const E_PHOFF: usize = 0x20;
+const E_PHENTSIZE: usize = 0x36;
+const E_PHNUM: usize = 0x38;
+
+// offsets into a program header
+const P_VADDR: usize = 0x10;
+const P_MEMSZ: usize = 0x28;
+const PROGRAM_HEADER_SIZE: usize = 0x38;
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(8)?)?;
+    let mut value = [0u8; 8];
+    value.copy_from_slice(bytes);
+    Some(u64::from_le_bytes(value))
+}
+
+/// Checks if `image` starts with the header of a 64-bit little endian ELF image.
+pub fn is_elf64(image: &[u8]) -> bool {
+    image.starts_with(ELF_MAGIC)
+        && image.get(E_IDENT_CLASS) == Some(&ELFCLASS64)
+        && image.get(E_IDENT_DATA) == Some(&ELFDATA2LSB)
+}
+
+/// Returns the virtual extent of the loadable segments of the 64-bit ELF image starting at `image`.
+///
+/// The size is 0 if the program headers are not part of `image`, e.g. because only the first page of
+/// a loaded image is available. Images that are not ELF64 or whose headers are inconsistent result in `None`.
+pub fn image_size(image: &[u8]) -> Option<u64> {
+    if !is_elf64(image) {
+        return None;
+    }
+
+    let phoff = usize::try_from(read_u64(image, E_PHOFF)?).ok();
+    let phentsize = read_u16(image, E_PHENTSIZE)? as usize;
+    let phnum = read_u16(image, E_PHNUM)? as usize;
+    if phnum > 0 && phentsize < PROGRAM_HEADER_SIZE {
+        return None;
+    }
+
+    let (mut start, mut end) = (u64::MAX, 0u64);
+    for i in 0..phnum {
+        let phdr = phoff.and_then(|phoff| phoff.checked_add(i.checked_mul(phentsize)?));
+        let p_type = match phdr.and_then(|phdr| read_u32(image, phdr)) {
+            Some(p_type) => p_type,
+            None => return Some(0),
+        };
+        if p_type != PT_LOAD {
+            continue;
+        }
+
+        let phdr = phdr?;
+        let vaddr = read_u64(image, phdr.checked_add(P_VADDR)?)?;
+        let memsz = read_u64(image, phdr.checked_add(P_MEMSZ)?)?;
+        start = start.min(vaddr);
+        end = end.max(vaddr.checked_add(memsz)?);
+    }
+    Some(end.saturating_sub(start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PT_NOTE: u32 = 4;
+
+    /// Builds the headers of an ELF64 image with the given (type, vaddr, memsz) program headers.
+    fn build(segments: &[(u32, u64, u64)]) -> Vec<u8> {
+        let phoff = 0x40;
+        let mut image = vec![0u8; phoff + segments.len() * PROGRAM_HEADER_SIZE];
+        image[..4].copy_from_slice(ELF_MAGIC);
+        image[E_IDENT_CLASS] = ELFCLASS64;
+        image[E_IDENT_DATA] = ELFDATA2LSB;
+        image[E_PHOFF..E_PHOFF + 8].copy_from_slice(&(phoff as u64).to_le_bytes());
+        image[E_PHENTSIZE..E_PHENTSIZE + 2]
+            .copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        image[E_PHNUM..E_PHNUM + 2].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        for (i, &(p_type, vaddr, memsz)) in segments.iter().enumerate() {
+            let phdr = phoff + i * PROGRAM_HEADER_SIZE;
+            image[phdr..phdr + 4].copy_from_slice(&p_type.to_le_bytes());
+            image[phdr + P_VADDR..phdr + P_VADDR + 8].copy_from_slice(&vaddr.to_le_bytes());
+            image[phdr + P_MEMSZ..phdr + P_MEMSZ + 8].copy_from_slice(&memsz.to_le_bytes());
+        }
+        image
+    }
+
+    fn set_u64(image: &mut [u8], offset: usize, value: u64) {
+        image[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn extent_of_load_segments() {
+        let image = build(&[
+            (PT_NOTE, 0x1000, 0x100000),
+            (PT_LOAD, 0xffffffff81000000, 0x1000000),
+            (PT_LOAD, 0xffffffff82000000, 0x800000),
+            (PT_NOTE, 0, 0x10),
+        ]);
+        assert_eq!(image_size(&image), Some(0x1800000));
+    }
+
+    #[test]
+    fn no_load_segments() {
+        assert_eq!(image_size(&build(&[])), Some(0));
+        assert_eq!(image_size(&build(&[(PT_NOTE, 0x1000, 0x1000)])), Some(0));
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        assert_eq!(image_size(b""), None);
+        assert_eq!(image_size(b"\x7fEL"), None);
+        assert_eq!(image_size(b"MZ\x90\0"), None);
+
+        // 32-bit and big endian images
+        let mut image = build(&[(PT_LOAD, 0x1000, 0x1000)]);
+        image[E_IDENT_CLASS] = 1;
+        assert_eq!(image_size(&image), None);
+        let mut image = build(&[(PT_LOAD, 0x1000, 0x1000)]);
+        image[E_IDENT_DATA] = 2;
+        assert_eq!(image_size(&image), None);
+    }
+
+    #[test]
+    fn program_headers_outside_of_image() {
+        let image = build(&[(PT_LOAD, 0x1000, 0x1000), (PT_LOAD, 0x2000, 0x1000)]);
+
+        // headers cut off by the end of the page
+        assert_eq!(image_size(&image[..0x40]), Some(0));
+        assert_eq!(image_size(&image[..0x40 + PROGRAM_HEADER_SIZE]), Some(0));
+
+        for phoff in [u64::MAX, u64::MAX - 0x10, 0x1000] {
+            let mut image = image.clone();
+            set_u64(&mut image, E_PHOFF, phoff);
+            assert_eq!(image_size(&image), Some(0));
+        }
+    }
+
+    #[test]
+    fn truncated_program_header() {
+        // the type is readable but vaddr or memsz are not
+        let image = build(&[(PT_LOAD, 0x1000, 0x1000)]);
+        let memsz_end = 0x40 + P_MEMSZ + 8;
+        for len in 0x44..memsz_end {
+            assert_eq!(image_size(&image[..len]), None, "len {:x}", len);
+        }
+        assert_eq!(image_size(&image[..memsz_end]), Some(0x1000));
+    }
+
+    #[test]
+    fn malformed_program_headers() {
+        // segment wrapping around the address space
+        let image = build(&[(PT_LOAD, u64::MAX - 0xfff, 0x2000)]);
+        assert_eq!(image_size(&image), None);
+
+        // entries smaller than a program header
+        let mut image = build(&[(PT_LOAD, 0x1000, 0x1000)]);
+        image[E_PHENTSIZE..E_PHENTSIZE + 2].copy_from_slice(&8u16.to_le_bytes());
+        assert_eq!(image_size(&image), None);
+
+        // maximum number of huge entries
+        let mut image = build(&[(PT_LOAD, 0x1000, 0x1000)]);
+        image[E_PHENTSIZE..E_PHENTSIZE + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        image[E_PHNUM..E_PHNUM + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(image_size(&image), Some(0));
+    }
+}
@@ -0,0 +1,10 @@
+//! Parsers and helpers of the memflow efi service that do not depend on uefi or the cpu.
+//!
+//! Everything in here only works on byte slices and plain values, so it can be unit tested on the host
+//! with `cargo test -p memflow-efi-core`.
+#![cfg_attr(not(test), no_std)]
+
+pub mod elf;
+pub mod page_runs;
+pub mod pe;
+pub mod xxhash;
@@ -0,0 +1,172 @@
+/// A run of virtually and physically contiguous pages with the same flags.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PageRun {
+    pub virt: u64,
+    pub size: u64,
+    pub phys: u64,
+    pub flags: u64,
+}
+
+impl PageRun {
+    /// Checks if the page directly follows this run in both address spaces.
+    fn is_continued_by(&self, virt: u64, phys: u64, flags: u64) -> bool {
+        self.virt.checked_add(self.size) == Some(virt)
+            && self.phys.checked_add(self.size) == Some(phys)
+            && self.flags == flags
+    }
+}
+
+/// Coalesces pages that are reported in ascending virtual order into runs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunCoalescer {
+    run: Option<PageRun>,
+}
+
+impl RunCoalescer {
+    /// Adds a page, returns the previous run if the page does not continue it.
+    pub fn push(&mut self, virt: u64, size: u64, phys: u64, flags: u64) -> Option<PageRun> {
+        if let Some(run) = self.run.as_mut() {
+            if run.is_continued_by(virt, phys, flags) {
+                run.size += size;
+                return None;
+            }
+        }
+        self.run.replace(PageRun {
+            virt,
+            size,
+            phys,
+            flags,
+        })
+    }
+
+    /// Returns the run that is still being extended.
+    pub fn open_run(&self) -> Option<PageRun> {
+        self.run
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNEL_BASE: u64 = 0xffff_8000_0000_0000;
+
+    fn coalesce(pages: &[(u64, u64, u64, u64)]) -> Vec<PageRun> {
+        let mut coalescer = RunCoalescer::default();
+        let mut runs: Vec<_> = pages
+            .iter()
+            .filter_map(|&(virt, size, phys, flags)| coalescer.push(virt, size, phys, flags))
+            .collect();
+        runs.extend(coalescer.open_run());
+        runs
+    }
+
+    fn run(virt: u64, size: u64, phys: u64, flags: u64) -> PageRun {
+        PageRun {
+            virt,
+            size,
+            phys,
+            flags,
+        }
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(coalesce(&[]), vec![]);
+    }
+
+    #[test]
+    fn contiguous_pages_are_merged() {
+        let pages = [
+            (0x1000, 0x1000, 0x5000, 2),
+            (0x2000, 0x1000, 0x6000, 2),
+            (0x3000, 0x1000, 0x7000, 2),
+        ];
+        assert_eq!(coalesce(&pages), vec![run(0x1000, 0x3000, 0x5000, 2)]);
+    }
+
+    #[test]
+    fn large_pages_are_merged() {
+        let pages = [
+            (0x1ff000, 0x1000, 0x3ff000, 0),
+            (0x200000, 0x200000, 0x400000, 0),
+            (0x400000, 0x1000, 0x600000, 0),
+        ];
+        assert_eq!(coalesce(&pages), vec![run(0x1ff000, 0x202000, 0x3ff000, 0)]);
+    }
+
+    #[test]
+    fn physical_gap_splits() {
+        let pages = [(0x1000, 0x1000, 0x5000, 0), (0x2000, 0x1000, 0x9000, 0)];
+        assert_eq!(
+            coalesce(&pages),
+            vec![
+                run(0x1000, 0x1000, 0x5000, 0),
+                run(0x2000, 0x1000, 0x9000, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn virtual_gap_splits() {
+        let pages = [(0x1000, 0x1000, 0x5000, 0), (0x3000, 0x1000, 0x6000, 0)];
+        assert_eq!(
+            coalesce(&pages),
+            vec![
+                run(0x1000, 0x1000, 0x5000, 0),
+                run(0x3000, 0x1000, 0x6000, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn flag_change_splits() {
+        let pages = [
+            (0x1000, 0x1000, 0x5000, 2),
+            (0x2000, 0x1000, 0x6000, 2 | 1 << 63),
+            (0x3000, 0x1000, 0x7000, 2 | 1 << 63),
+        ];
+        assert_eq!(
+            coalesce(&pages),
+            vec![
+                run(0x1000, 0x1000, 0x5000, 2),
+                run(0x2000, 0x2000, 0x6000, 2 | 1 << 63)
+            ]
+        );
+    }
+
+    #[test]
+    fn canonical_hole_splits() {
+        // the last user page and the first kernel page are physically contiguous
+        let pages = [
+            (0x7fff_ffff_f000, 0x1000, 0x5000, 0),
+            (KERNEL_BASE, 0x1000, 0x6000, 0),
+        ];
+        assert_eq!(coalesce(&pages).len(), 2);
+    }
+
+    #[test]
+    fn end_of_address_space() {
+        let pages = [
+            (u64::MAX - 0x1fff, 0x1000, 0x5000, 0),
+            (u64::MAX - 0xfff, 0x1000, 0x6000, 0),
+        ];
+        assert_eq!(
+            coalesce(&pages),
+            vec![run(u64::MAX - 0x1fff, 0x2000, 0x5000, 0)]
+        );
+    }
+
+    #[test]
+    fn push_returns_finished_run() {
+        let mut coalescer = RunCoalescer::default();
+        assert_eq!(coalescer.push(0x1000, 0x1000, 0x5000, 0), None);
+        assert_eq!(coalescer.push(0x2000, 0x1000, 0x6000, 0), None);
+        assert_eq!(
+            coalescer.push(0x4000, 0x1000, 0x8000, 0),
+            Some(run(0x1000, 0x2000, 0x5000, 0))
+        );
+        assert_eq!(coalescer.open_run(), Some(run(0x4000, 0x1000, 0x8000, 0)));
+    }
+}
@@ -0,0 +1,416 @@
+const IMAGE_DOS_SIGNATURE: &[u8] = b"MZ";
+const IMAGE_NT_SIGNATURE: &[u8] = b"PE\0\0";
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
+
+const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+
+// offsets into the nt headers
+const FILE_HEADER_OFFSET: usize = 4;
+const OPTIONAL_HEADER_OFFSET: usize = 24;
+const SIZE_OF_IMAGE_OFFSET: usize = OPTIONAL_HEADER_OFFSET + 56;
+const NUMBER_OF_RVA_AND_SIZES_OFFSET: usize = OPTIONAL_HEADER_OFFSET + 108;
+const DATA_DIRECTORY_OFFSET: usize = OPTIONAL_HEADER_OFFSET + 112;
+
+const SECTION_HEADER_SIZE: usize = 40;
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads the zero terminated string at `offset`.
+fn read_cstr(data: &[u8], offset: usize) -> Option<&str> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|b| *b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// Identifies an export.
+#[derive(Clone, Copy, Debug)]
+pub enum ExportId<'a> {
+    Name(&'a str),
+    Ordinal(u16),
+}
+
+/// A resolved export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Export<'a> {
+    /// The export is implemented by the image at the given rva.
+    Rva(u32),
+    /// The export is forwarded to another image, e.g. `NTDLL.RtlAllocateHeap` or `NTDLL.#12`.
+    Forwarder(&'a str),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Section<'a> {
+    pub name: &'a [u8],
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub characteristics: u32,
+}
+
+/// Minimal parser for mapped PE32+ images.
+///
+/// The image is expected in its loaded layout, i.e. rvas are offsets into the slice.
+/// All accesses are bounds checked, malformed images result in `None` instead of a panic.
+#[derive(Clone, Copy)]
+pub struct PeImage<'a> {
+    image: &'a [u8],
+    nt_headers: usize,
+}
+
+impl<'a> PeImage<'a> {
+    /// Validates the dos and nt headers of a PE32+ image.
+    pub fn parse(image: &'a [u8]) -> Option<Self> {
+        if !image.starts_with(IMAGE_DOS_SIGNATURE) {
+            return None;
+        }
+
+        let nt_headers = read_u32(image, 0x3c)? as usize;
+        if image.get(nt_headers..nt_headers.checked_add(4)?)? != IMAGE_NT_SIGNATURE {
+            return None;
+        }
+        if read_u16(image, nt_headers + OPTIONAL_HEADER_OFFSET)? != IMAGE_NT_OPTIONAL_HDR64_MAGIC {
+            return None;
+        }
+
+        Some(Self { image, nt_headers })
+    }
+
+    pub fn size_of_image(&self) -> Option<u32> {
+        read_u32(self.image, self.nt_headers + SIZE_OF_IMAGE_OFFSET)
+    }
+
+    /// Returns the rva and size of the given data directory.
+    pub fn data_directory(&self, index: usize) -> Option<(u32, u32)> {
+        let count = read_u32(self.image, self.nt_headers + NUMBER_OF_RVA_AND_SIZES_OFFSET)?;
+        if index >= count as usize {
+            return None;
+        }
+        let entry = self.nt_headers + DATA_DIRECTORY_OFFSET + index * 8;
+        let rva = read_u32(self.image, entry)?;
+        let size = read_u32(self.image, entry + 4)?;
+        if rva == 0 {
+            return None;
+        }
+        Some((rva, size))
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = Section<'a>> + 'a {
+        let image = self.image;
+        let file_header = self.nt_headers + FILE_HEADER_OFFSET;
+        let count = read_u16(image, file_header + 2).unwrap_or(0) as usize;
+        let optional_size = read_u16(image, file_header + 16).unwrap_or(0) as usize;
+        let first = self.nt_headers + OPTIONAL_HEADER_OFFSET + optional_size;
+
+        (0..count).map_while(move |i| {
+            let header = first + i * SECTION_HEADER_SIZE;
+            Some(Section {
+                name: image.get(header..header + 8)?,
+                virtual_size: read_u32(image, header + 8)?,
+                virtual_address: read_u32(image, header + 12)?,
+                characteristics: read_u32(image, header + 36)?,
+            })
+        })
+    }
+
+    /// Returns the section containing `rva`.
+    pub fn section_of(&self, rva: u32) -> Option<Section<'a>> {
+        self.sections()
+            .find(|s| s.virtual_address <= rva && (rva - s.virtual_address) < s.virtual_size.max(1))
+    }
+
+    /// Resolves an export by name or ordinal.
+    pub fn find_export(&self, id: ExportId) -> Option<Export<'a>> {
+        let image = self.image;
+        let (dir, dir_size) = self.data_directory(IMAGE_DIRECTORY_ENTRY_EXPORT)?;
+        let dir = dir as usize;
+
+        let base = read_u32(image, dir + 16)?;
+        let number_of_functions = read_u32(image, dir + 20)?;
+        let number_of_names = read_u32(image, dir + 24)? as usize;
+        let functions = read_u32(image, dir + 28)? as usize;
+        let names = read_u32(image, dir + 32)? as usize;
+        let name_ordinals = read_u32(image, dir + 36)? as usize;
+
+        let index = match id {
+            ExportId::Ordinal(ordinal) => (ordinal as u32).checked_sub(base)?,
+            ExportId::Name(name) => {
+                // the walk ends with the name table, regardless of the claimed count
+                let i = (0..number_of_names)
+                    .map_while(|i| read_u32(image, names + i * 4))
+                    .position(|rva| read_cstr(image, rva as usize) == Some(name))?;
+                read_u16(image, name_ordinals + i * 2)? as u32
+            }
+        };
+        if index >= number_of_functions {
+            return None;
+        }
+
+        let rva = read_u32(image, functions + index as usize * 4)?;
+        if rva == 0 {
+            return None;
+        }
+
+        // forwarders point into the export directory itself
+        if (rva as usize) >= dir && (rva as usize) < dir + dir_size as usize {
+            return read_cstr(image, rva as usize).map(Export::Forwarder);
+        }
+        Some(Export::Rva(rva))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE_OF_HEADERS_OFFSET: usize = OPTIONAL_HEADER_OFFSET + 60;
+
+    /// Dll with exports, see `fixtures/README.md`.
+    const EXPORTS_DLL: &[u8] = include_bytes!("../fixtures/exports.dll");
+    /// Efi application without exports whose file alignment matches its section alignment.
+    const BOOTX64_EFI: &[u8] = include_bytes!("../../scripts/efi_include/EFI/Boot/Bootx64.efi");
+
+    /// Lays out the sections of a PE file like the loader does.
+    fn map_image(file: &[u8]) -> Vec<u8> {
+        let image = PeImage::parse(file).unwrap();
+        let mut mapped = vec![0u8; image.size_of_image().unwrap() as usize];
+
+        let headers = read_u32(file, image.nt_headers + SIZE_OF_HEADERS_OFFSET).unwrap() as usize;
+        mapped[..headers].copy_from_slice(&file[..headers]);
+
+        let file_header = image.nt_headers + FILE_HEADER_OFFSET;
+        let optional_size = read_u16(file, file_header + 16).unwrap() as usize;
+        let first = image.nt_headers + OPTIONAL_HEADER_OFFSET + optional_size;
+        for section in image.sections() {
+            let header = (0..)
+                .map(|i| first + i * SECTION_HEADER_SIZE)
+                .find(|&header| read_u32(file, header + 12) == Some(section.virtual_address))
+                .unwrap();
+            let raw_size = read_u32(file, header + 16).unwrap() as usize;
+            let raw_ptr = read_u32(file, header + 20).unwrap() as usize;
+            let len = raw_size.min(section.virtual_size as usize);
+            let va = section.virtual_address as usize;
+            mapped[va..va + len].copy_from_slice(&file[raw_ptr..raw_ptr + len]);
+        }
+        mapped
+    }
+
+    fn set_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn export_dir(image: &[u8]) -> usize {
+        PeImage::parse(image)
+            .unwrap()
+            .data_directory(IMAGE_DIRECTORY_ENTRY_EXPORT)
+            .unwrap()
+            .0 as usize
+    }
+
+    #[test]
+    fn exports_dll_headers() {
+        let mapped = map_image(EXPORTS_DLL);
+        let image = PeImage::parse(&mapped).unwrap();
+        assert_eq!(image.size_of_image(), Some(0x3000));
+        assert_eq!(
+            image.data_directory(IMAGE_DIRECTORY_ENTRY_EXPORT),
+            Some((0x2004, 0xaa))
+        );
+
+        let sections: Vec<_> = image
+            .sections()
+            .map(|s| (s.name, s.virtual_address))
+            .collect();
+        assert_eq!(
+            sections,
+            vec![(&b".text\0\0\0"[..], 0x1000), (&b".rdata\0\0"[..], 0x2000)]
+        );
+        assert_eq!(image.section_of(0x1004).unwrap().name, b".text\0\0\0");
+        assert_eq!(image.section_of(0x2000).unwrap().name, b".rdata\0\0");
+        assert!(image.section_of(0x800).is_none());
+        assert!(image.section_of(0x3000).is_none());
+    }
+
+    #[test]
+    fn exports_dll_exports() {
+        let mapped = map_image(EXPORTS_DLL);
+        let image = PeImage::parse(&mapped).unwrap();
+
+        let by_name = |name| image.find_export(ExportId::Name(name));
+        assert_eq!(by_name("fixture_add"), Some(Export::Rva(0x1000)));
+        assert_eq!(by_name("fixture_answer"), Some(Export::Rva(0x1004)));
+        assert_eq!(by_name("fixture_data"), Some(Export::Rva(0x2000)));
+        assert_eq!(
+            by_name("fixture_forward"),
+            Some(Export::Forwarder("kernel32.GetTickCount"))
+        );
+        assert_eq!(by_name("fixture"), None);
+        assert_eq!(by_name("fixture_add_"), None);
+        assert_eq!(by_name(""), None);
+
+        let by_ordinal = |ordinal| image.find_export(ExportId::Ordinal(ordinal));
+        assert_eq!(by_ordinal(1), Some(Export::Rva(0x1000)));
+        assert_eq!(by_ordinal(3), Some(Export::Rva(0x2000)));
+        assert_eq!(
+            by_ordinal(4),
+            Some(Export::Forwarder("kernel32.GetTickCount"))
+        );
+        assert_eq!(by_ordinal(0), None);
+        assert_eq!(by_ordinal(5), None);
+        assert_eq!(by_ordinal(u16::MAX), None);
+    }
+
+    #[test]
+    fn image_without_exports() {
+        let image = PeImage::parse(BOOTX64_EFI).unwrap();
+        assert_eq!(image.size_of_image(), Some(0xe85c0));
+        assert_eq!(image.data_directory(IMAGE_DIRECTORY_ENTRY_EXPORT), None);
+        assert_eq!(image.find_export(ExportId::Name("efi_main")), None);
+        assert_eq!(image.find_export(ExportId::Ordinal(1)), None);
+
+        let sections: Vec<_> = image.sections().map(|s| s.virtual_address).collect();
+        assert_eq!(sections, vec![0x280, 0x67080, 0xe31e0, 0xe5aa0, 0xe7360]);
+        assert_eq!(image.section_of(0x1000).unwrap().name, b".text\0\0\0");
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        assert!(PeImage::parse(b"").is_none());
+        assert!(PeImage::parse(b"MZ").is_none());
+        assert!(PeImage::parse(b"\x7fELF\x02\x01\x01\0").is_none());
+        assert!(PeImage::parse(&[0u8; 0x1000]).is_none());
+    }
+
+    #[test]
+    fn truncated_images() {
+        let mapped = map_image(EXPORTS_DLL);
+        let full = PeImage::parse(&mapped).unwrap();
+        let ids = [
+            ExportId::Name("fixture_add"),
+            ExportId::Name("fixture_forward"),
+            ExportId::Ordinal(2),
+        ];
+
+        for len in 0..mapped.len() {
+            let image = match PeImage::parse(&mapped[..len]) {
+                Some(image) => image,
+                None => {
+                    assert!(
+                        len <= full.nt_headers + OPTIONAL_HEADER_OFFSET + 2,
+                        "len {:x}",
+                        len
+                    );
+                    continue;
+                }
+            };
+
+            // lookups fail once their data is cut off, but they never return something else
+            let _ = image.size_of_image();
+            assert!(image.sections().count() <= full.sections().count());
+            for id in ids {
+                let export = image.find_export(id);
+                assert!(
+                    export.is_none() || export == full.find_export(id),
+                    "len {:x}",
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_nt_headers() {
+        let mapped = map_image(EXPORTS_DLL);
+        let nt_headers = PeImage::parse(&mapped).unwrap().nt_headers;
+
+        for e_lfanew in [u32::MAX, mapped.len() as u32 - 2, 0] {
+            let mut image = mapped.clone();
+            set_u32(&mut image, 0x3c, e_lfanew);
+            assert!(PeImage::parse(&image).is_none());
+        }
+
+        let mut image = mapped.clone();
+        image[nt_headers + 2] = b'X';
+        assert!(PeImage::parse(&image).is_none());
+
+        // PE32 images are not supported
+        let mut image = mapped.clone();
+        image[nt_headers + OPTIONAL_HEADER_OFFSET] = 0x0b;
+        image[nt_headers + OPTIONAL_HEADER_OFFSET + 1] = 0x01;
+        assert!(PeImage::parse(&image).is_none());
+
+        let mut image = mapped.clone();
+        set_u32(&mut image, nt_headers + NUMBER_OF_RVA_AND_SIZES_OFFSET, 0);
+        let pe = PeImage::parse(&image).unwrap();
+        assert_eq!(pe.data_directory(IMAGE_DIRECTORY_ENTRY_EXPORT), None);
+        assert_eq!(pe.find_export(ExportId::Name("fixture_add")), None);
+
+        // more sections than the image holds
+        let mut image = mapped.clone();
+        let file_header = nt_headers + FILE_HEADER_OFFSET;
+        image[file_header + 2..file_header + 4].copy_from_slice(&u16::MAX.to_le_bytes());
+        let pe = PeImage::parse(&image).unwrap();
+        assert!(pe.sections().count() < mapped.len() / SECTION_HEADER_SIZE);
+        assert!(pe.section_of(u32::MAX).is_none());
+    }
+
+    #[test]
+    fn malformed_export_directory() {
+        let mapped = map_image(EXPORTS_DLL);
+        let nt_headers = PeImage::parse(&mapped).unwrap().nt_headers;
+        let dir = export_dir(&mapped);
+
+        // directory outside of the image
+        for rva in [u32::MAX, u32::MAX - 16, mapped.len() as u32 - 4] {
+            let mut image = mapped.clone();
+            set_u32(&mut image, nt_headers + DATA_DIRECTORY_OFFSET, rva);
+            let pe = PeImage::parse(&image).unwrap();
+            assert_eq!(pe.find_export(ExportId::Name("fixture_add")), None);
+            assert_eq!(pe.find_export(ExportId::Ordinal(1)), None);
+        }
+
+        // tables outside of the image
+        for field in [28, 32, 36] {
+            for rva in [u32::MAX, u32::MAX - 3, mapped.len() as u32] {
+                let mut image = mapped.clone();
+                set_u32(&mut image, dir + field, rva);
+                let pe = PeImage::parse(&image).unwrap();
+                assert_eq!(pe.find_export(ExportId::Name("fixture_add")), None);
+            }
+        }
+
+        // counts exceeding the tables
+        let mut image = mapped.clone();
+        set_u32(&mut image, dir + 20, u32::MAX);
+        set_u32(&mut image, dir + 24, u32::MAX);
+        let pe = PeImage::parse(&image).unwrap();
+        assert_eq!(pe.find_export(ExportId::Name("missing")), None);
+        assert_eq!(pe.find_export(ExportId::Ordinal(u16::MAX)), None);
+
+        // name pointing at a string that is not terminated within the image
+        let mut image = mapped.clone();
+        let names = read_u32(&image, dir + 32).unwrap() as usize;
+        set_u32(&mut image, names, mapped.len() as u32 - 1);
+        let last = image.len() - 1;
+        image[last] = b'x';
+        let pe = PeImage::parse(&image).unwrap();
+        assert_eq!(pe.find_export(ExportId::Name("fixture_add")), None);
+        assert_eq!(pe.find_export(ExportId::Name("x")), None);
+
+        // ordinal base above the requested ordinal
+        let mut image = mapped.clone();
+        set_u32(&mut image, dir + 16, 10);
+        let pe = PeImage::parse(&image).unwrap();
+        assert_eq!(pe.find_export(ExportId::Ordinal(1)), None);
+        assert_eq!(
+            pe.find_export(ExportId::Ordinal(10)),
+            Some(Export::Rva(0x1000))
+        );
+    }
+}
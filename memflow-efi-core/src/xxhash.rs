@@ -0,0 +1,213 @@
+const PRIME64_1: u64 = 0x9e3779b185ebca87;
+const PRIME64_2: u64 = 0xc2b2ae3d27d4eb4f;
+const PRIME64_3: u64 = 0x165667b19e3779f9;
+const PRIME64_4: u64 = 0x85ebca77c2b2ae63;
+const PRIME64_5: u64 = 0x27d4eb2f165667c5;
+
+const STRIPE_LEN: usize = 32;
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn merge_round(acc: u64, val: u64) -> u64 {
+    (acc ^ round(0, val))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+/// Hash state that can be updated with data in pieces.
+///
+/// The state is plain data so it can be handed to the caller between chunks of a command.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Xxh64 {
+    seed: u64,
+    total_len: u64,
+    acc: [u64; 4],
+    buf: [u8; STRIPE_LEN],
+    buf_len: u64,
+}
+
+impl Xxh64 {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            total_len: 0,
+            acc: [
+                seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+                seed.wrapping_add(PRIME64_2),
+                seed,
+                seed.wrapping_sub(PRIME64_1),
+            ],
+            buf: [0; STRIPE_LEN],
+            buf_len: 0,
+        }
+    }
+
+    /// Returns false if the state cannot have been produced by `new` and `update`.
+    ///
+    /// States received from elsewhere have to be checked before they are used.
+    pub fn is_valid(&self) -> bool {
+        self.buf_len <= STRIPE_LEN as u64
+    }
+
+    fn consume_stripe(&mut self, stripe: &[u8]) {
+        for (i, acc) in self.acc.iter_mut().enumerate() {
+            *acc = round(*acc, read_u64(&stripe[i * 8..]));
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len = self.total_len.wrapping_add(data.len() as u64);
+
+        // complete a partially filled stripe first
+        let buf_len = self.buf_len as usize;
+        if buf_len > 0 {
+            let fill = (STRIPE_LEN - buf_len).min(data.len());
+            self.buf[buf_len..buf_len + fill].copy_from_slice(&data[..fill]);
+            self.buf_len += fill as u64;
+            data = &data[fill..];
+            if (self.buf_len as usize) < STRIPE_LEN {
+                return;
+            }
+            let stripe = self.buf;
+            self.consume_stripe(&stripe);
+            self.buf_len = 0;
+        }
+
+        let (stripes, rest) = data.as_chunks::<STRIPE_LEN>();
+        for stripe in stripes {
+            self.consume_stripe(stripe);
+        }
+
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.buf_len = rest.len() as u64;
+    }
+
+    pub fn digest(&self) -> u64 {
+        let mut hash = if self.total_len >= STRIPE_LEN as u64 {
+            let [v1, v2, v3, v4] = self.acc;
+            let hash = v1
+                .rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18));
+            self.acc.iter().fold(hash, |hash, v| merge_round(hash, *v))
+        } else {
+            self.seed.wrapping_add(PRIME64_5)
+        };
+        hash = hash.wrapping_add(self.total_len);
+
+        let mut rest = &self.buf[..self.buf_len as usize];
+        while rest.len() >= 8 {
+            hash ^= round(0, read_u64(rest));
+            hash = hash
+                .rotate_left(27)
+                .wrapping_mul(PRIME64_1)
+                .wrapping_add(PRIME64_4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            hash ^= (read_u32(rest) as u64).wrapping_mul(PRIME64_1);
+            hash = hash
+                .rotate_left(23)
+                .wrapping_mul(PRIME64_2)
+                .wrapping_add(PRIME64_3);
+            rest = &rest[4..];
+        }
+        for byte in rest {
+            hash ^= (*byte as u64).wrapping_mul(PRIME64_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME64_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME64_3);
+        hash ^= hash >> 32;
+        hash
+    }
+}
+
+/// Hashes `data` in one go.
+pub fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let mut state = Xxh64::new(seed);
+    state.update(data);
+    state.digest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0, 1, ..., 255 repeated four times
+    fn counting_bytes() -> Vec<u8> {
+        (0..1024).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn reference_vectors() {
+        assert_eq!(xxh64(b"", 0), 0xef46db3751d8e999);
+        assert_eq!(xxh64(b"a", 0), 0xd24ec4f1a98c6e5b);
+        assert_eq!(xxh64(b"abc", 0), 0x44bc2cf5ad770999);
+        assert_eq!(
+            xxh64(b"Nobody inspects the spammish repetition", 0),
+            0xfbcea83c8a378bf1
+        );
+        assert_eq!(xxh64(&counting_bytes(), 0), 0x6f3914f18fe4df57);
+    }
+
+    #[test]
+    fn reference_vectors_with_seed() {
+        assert_eq!(xxh64(b"xxhash", 20141025), 0xb559b98d844e0635);
+        assert_eq!(
+            xxh64(&counting_bytes(), 0x9e3779b97f4a7c15),
+            0x22d0f4503bcda26a
+        );
+    }
+
+    #[test]
+    fn chunked_update_matches_single_update() {
+        let data = counting_bytes();
+        for chunk_size in [1, 3, 8, 31, 32, 33, 100, 4096] {
+            let mut state = Xxh64::new(7);
+            for chunk in data.chunks(chunk_size) {
+                state.update(chunk);
+            }
+            assert_eq!(state.digest(), xxh64(&data, 7), "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn digest_does_not_consume_state() {
+        let mut state = Xxh64::new(0);
+        state.update(b"ab");
+        let _ = state.digest();
+        state.update(b"c");
+        assert_eq!(state.digest(), xxh64(b"abc", 0));
+    }
+
+    #[test]
+    fn rejects_corrupted_state() {
+        let mut state = Xxh64::new(0);
+        state.update(b"abc");
+        assert!(state.is_valid());
+        state.buf_len = STRIPE_LEN as u64 + 1;
+        assert!(!state.is_valid());
+    }
+}
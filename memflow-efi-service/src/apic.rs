@@ -0,0 +1,102 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use ::r_efi::*;
+use x86_64::registers::{control::Cr3, model_specific::Msr};
+
+use crate::{boot_services_available, identity_page_table::IdentityPageTable, IDENTITY_PAGE_TABLE};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Id and interrupt command registers in x2apic mode.
+const X2APIC_ID: u32 = 0x802;
+const X2APIC_ICR: u32 = 0x830;
+/// Offsets of the id and interrupt command registers in the xapic mmio page.
+const XAPIC_ID: u64 = 0x20;
+const XAPIC_ICR_LOW: u64 = 0x300;
+const XAPIC_ICR_HIGH: u64 = 0x310;
+
+const ICR_DELIVERY_NMI: u64 = 0b100 << 8;
+const ICR_DELIVERY_PENDING: u64 = 1 << 12;
+const ICR_LEVEL_ASSERT: u64 = 1 << 14;
+
+/// Number of TSC ticks to wait for a previous ipi to be sent.
+const ICR_WAIT_TICKS: u64 = 0x100_0000;
+
+/// Physical address of the xapic recorded at boot, its page is mapped uncached in the identity mapping.
+static XAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Records the apic and maps the xapic into the identity mapping.
+///
+/// This has to be called while boot services are available.
+pub fn init(identity_page_table: &mut IdentityPageTable) -> Result<(), &'static str> {
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    if apic_base & APIC_BASE_ENABLE == 0 {
+        return Err("local apic is disabled");
+    }
+
+    let xapic_base = apic_base & APIC_BASE_MASK;
+    identity_page_table.map_mmio(xapic_base, 0x1000)?;
+    XAPIC_BASE.store(xapic_base, Ordering::SeqCst);
+
+    info!("local apic at {:x}", xapic_base);
+    Ok(())
+}
+
+/// Forgets the xapic, used when the identity mapping is released.
+pub fn reset() {
+    XAPIC_BASE.store(0, Ordering::SeqCst);
+}
+
+/// Returns true if physical memory, and thus the xapic page, is identity mapped.
+fn xapic_reachable() -> bool {
+    boot_services_available()
+        || Cr3::read().0 == unsafe { (*core::ptr::addr_of!(IDENTITY_PAGE_TABLE)).dtb() }
+}
+
+/// Sends an nmi to the current cpu.
+///
+/// In xapic mode this requires the identity mapping to be active or boot services to be available,
+/// `NOT_READY` is returned otherwise.
+pub fn send_self_nmi() -> Result<(), efi::Status> {
+    let icr = ICR_LEVEL_ASSERT | ICR_DELIVERY_NMI;
+
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    if apic_base & APIC_BASE_X2APIC != 0 {
+        unsafe {
+            let id = Msr::new(X2APIC_ID).read();
+            Msr::new(X2APIC_ICR).write(id << 32 | icr);
+        }
+        return Ok(());
+    }
+
+    // the OS might have moved the xapic, only the page mapped at boot is accessible
+    let xapic_base = XAPIC_BASE.load(Ordering::SeqCst);
+    if xapic_base == 0 || apic_base & APIC_BASE_MASK != xapic_base {
+        return Err(efi::Status::UNSUPPORTED);
+    }
+    if !xapic_reachable() {
+        return Err(efi::Status::NOT_READY);
+    }
+
+    let id = (xapic_base + XAPIC_ID) as *const u32;
+    let icr_low = (xapic_base + XAPIC_ICR_LOW) as *mut u32;
+    let icr_high = (xapic_base + XAPIC_ICR_HIGH) as *mut u32;
+    unsafe {
+        let deadline = _rdtsc() + ICR_WAIT_TICKS;
+        while icr_low.read_volatile() as u64 & ICR_DELIVERY_PENDING != 0 {
+            if _rdtsc() >= deadline {
+                return Err(efi::Status::TIMEOUT);
+            }
+            core::hint::spin_loop();
+        }
+        // the destination is in bits 24..32 of both registers
+        icr_high.write_volatile(id.read_volatile() & 0xff00_0000);
+        icr_low.write_volatile(icr as u32);
+    }
+
+    Ok(())
+}
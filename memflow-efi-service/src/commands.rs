@@ -0,0 +1,1169 @@
+use core::ffi::c_void;
+#[cfg(feature = "writes")]
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use ::r_efi::*;
+use memflow_efi_core::{
+    elf,
+    page_runs::{PageRun, RunCoalescer},
+    pe::PeImage,
+    xxhash::{xxh64, Xxh64},
+};
+use x86_64::{
+    instructions::tables::{sgdt, sidt},
+    registers::{
+        control::{Cr0, Cr4},
+        model_specific::Msr,
+    },
+    structures::paging::PhysFrame,
+};
+
+use crate::{
+    cr3::{first_kernel_dtb, load_identity, matches_kernel_half, resolve_kernel_dtb, CallerCr3},
+    firmware_tables::{self, AcpiTableEntry, FirmwareTables},
+    framebuffer::{self, Framebuffer},
+    images::{self, ImageEntry},
+    mem_maps::{EfiMemMaps, OS_MEMORY_TYPE_MASK},
+    pci::{self, PciDevice},
+    percpu::apic_id,
+    utils,
+    vtop::{virt_to_phys, walk_mapped_pages},
+    EFI_MEM_MAPS, IDENTITY_PAGE_TABLE,
+};
+
+/// Magic value identifying a memflow command.
+pub const MEMFLOW_MAGIC: u32 = 0x2b54a004;
+
+/// Reads `len` bytes of physical memory at `src` into the caller buffer `dst`.
+pub const CMD_READ_PHYS: u32 = 0;
+/// Copies the caller's kernel half into the identity mapping again, regardless of whether it changed.
+pub const CMD_RESYNC_KERNEL: u32 = 1;
+
+/// Atomically modifies the physical memory at `src` as described by the `AtomicArgs` in the payload.
+///
+/// This command is only available with the `writes` feature.
+pub const CMD_ATOMIC_PHYS: u32 = 2;
+
+/// Scans the physical range `src`..`src + len` for the pattern in the `ScanArgs` of the payload.
+///
+/// The physical addresses of matches are written as u64 to `dst`, which has to hold `max_hits` entries.
+pub const CMD_SCAN_PHYS: u32 = 3;
+
+/// Hashes the physical range `src`..`src + len` with xxHash64 as described by the `HashArgs` in the payload.
+pub const CMD_HASH_PHYS: u32 = 4;
+
+/// Enumerates the mapped virtual ranges of the address space with the dtb `src`.
+///
+/// Coalesced `PageMapEntry` runs are written to `dst`, progress is tracked by the `PageMapArgs` in the payload.
+pub const CMD_PAGE_MAP: u32 = 5;
+
+/// Scans the physical range `src`..`src + len` for top level tables sharing the kernel half
+/// of the first kernel dtb, see `DtbArgs`.
+///
+/// The physical addresses of candidates are written as u64 to `dst`, which has to hold `max_hits` entries.
+pub const CMD_FIND_DTB: u32 = 6;
+
+/// Captures the `CpuContext` of the cpu servicing the call into the payload.
+///
+/// This command runs in the caller's context and does not enter the identity mapping.
+pub const CMD_CPU_CONTEXT: u32 = 7;
+
+/// Locates the kernel image containing IA32_LSTAR, see `KernelBaseArgs`.
+///
+/// The `len` bytes below IA32_LSTAR are scanned for the image headers, `KERNEL_SCAN_SIZE` covers common kernels.
+/// `NOT_FOUND` is returned once the whole range has been scanned without a match.
+pub const CMD_KERNEL_BASE: u32 = 8;
+
+/// Copies the `ImageEntry`s of the images loaded during boot to `dst`, see `ListArgs`.
+pub const CMD_IMAGE_LIST: u32 = 9;
+
+/// Stores the `FirmwareTablesArgs` in the payload and copies up to `len` bytes of `AcpiTableEntry`s to `dst`.
+///
+/// `dst` may be null if only the entry points are of interest.
+pub const CMD_FIRMWARE_TABLES: u32 = 10;
+
+/// Copies the `PciDevice`s recorded during boot to `dst`, see `ListArgs`.
+pub const CMD_PCI_DEVICES: u32 = 11;
+
+/// Stores the `Framebuffer` of the graphics mode set during boot in the payload.
+///
+/// Reads of the framebuffer are permitted even though it is device memory.
+pub const CMD_FRAMEBUFFER: u32 = 12;
+
+/// Resolves the export named in the `ExportArgs` of the payload in the image at the physical address `src`.
+///
+/// The image is `len` bytes long and has to reside in a single memory map,
+/// e.g. an image reported by `CMD_IMAGE_LIST`. Forwarded exports are reported as `NOT_FOUND`.
+pub const CMD_FIND_EXPORT: u32 = 13;
+
+/// Command header passed as the data of the SetVariable call.
+///
+/// Unless stated otherwise `dst` and `len` describe the caller buffer that receives the output.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MemflowCommand {
+    pub magic: u32,
+    pub command: u32,
+    pub src: *const c_void,
+    pub dst: *mut c_void,
+    pub len: usize,
+}
+const _: [(); core::mem::size_of::<MemflowCommand>()] = [(); 32];
+
+/// Maximum size of the payload following the header that is passed to a command.
+pub const MAX_PAYLOAD_SIZE: usize = 0x200;
+
+/// Number of bytes a chunked command processes per entry into the identity mapping.
+///
+/// Interrupts are enabled and the caller's cr3 is restored between chunks.
+pub const CHUNK_SIZE: usize = 0x10000;
+
+/// Optional arguments of `CMD_READ_PHYS`, following the header in the SetVariable data.
+/// All other chunked commands start their arguments with it,
+/// they return `NO_MAPPING` if their output buffer `dst` cannot be mapped.
+///
+/// Both fields are updated in place when the command returns.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadArgs {
+    /// Maximum number of TSC ticks spent in a single call, 0 means unlimited.
+    pub budget: u64,
+    /// Offset into the request to continue from, receives the offset reached.
+    ///
+    /// If the budget is exhausted before the request is done `TIMEOUT` is returned
+    /// and the caller is expected to issue the command again with the returned cursor.
+    pub cursor: u64,
+}
+
+impl CommandArgs for ReadArgs {}
+
+/// Compares `expected` with the value at `src` and replaces it with `operand` if they are equal.
+pub const ATOMIC_CMPXCHG: u32 = 0;
+/// Adds `operand` to the value at `src`, wrapping around on overflow.
+pub const ATOMIC_ADD: u32 = 1;
+/// Ors `operand` into the value at `src`.
+pub const ATOMIC_OR: u32 = 2;
+/// Ands `operand` into the value at `src`.
+pub const ATOMIC_AND: u32 = 3;
+
+/// Arguments of `CMD_ATOMIC_PHYS`, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AtomicArgs {
+    /// One of the `ATOMIC_*` operations.
+    pub op: u32,
+    /// Operand size in bytes: 1, 2, 4 or 8. `src` has to be aligned to it.
+    pub size: u32,
+    pub operand: u64,
+    /// Value compared against by `ATOMIC_CMPXCHG`.
+    pub expected: u64,
+    /// Receives the value at `src` before the operation.
+    ///
+    /// A compare-exchange succeeded if it is equal to `expected`.
+    pub previous: u64,
+}
+
+impl CommandArgs for AtomicArgs {}
+
+/// Maximum length of a scan pattern.
+pub const MAX_PATTERN_LEN: usize = 64;
+
+/// Arguments of `CMD_SCAN_PHYS`, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ScanArgs {
+    pub progress: ReadArgs,
+    /// Number of entries `dst` can hold.
+    pub max_hits: u64,
+    /// Number of hits written to `dst`, new hits are appended.
+    ///
+    /// Once `dst` is full `BUFFER_TOO_SMALL` is returned, the caller resets this field
+    /// after consuming the hits and continues from the returned cursor.
+    pub hits: u64,
+    /// Matches are only reported at addresses aligned to this power of two, 0 means any address.
+    pub alignment: u64,
+    /// Bit n selects memory maps of type n, 0 selects the memory that can be read.
+    pub type_mask: u64,
+    pub pattern_len: u64,
+    pub pattern: [u8; MAX_PATTERN_LEN],
+    /// Bits that have to match the pattern, 0x00 makes a byte a wildcard.
+    pub mask: [u8; MAX_PATTERN_LEN],
+}
+
+impl Default for ScanArgs {
+    fn default() -> Self {
+        Self {
+            progress: ReadArgs::default(),
+            max_hits: 0,
+            hits: 0,
+            alignment: 0,
+            type_mask: 0,
+            pattern_len: 0,
+            pattern: [0; MAX_PATTERN_LEN],
+            mask: [0; MAX_PATTERN_LEN],
+        }
+    }
+}
+
+impl CommandArgs for ScanArgs {}
+
+/// Writes one digest per page to `dst`, `src` has to be page aligned.
+///
+/// Pages that cannot be read get a digest of 0.
+pub const HASH_PER_PAGE: u32 = 0;
+/// Computes a single digest of the whole range, pages that cannot be read are hashed as zeroes.
+pub const HASH_RANGE: u32 = 1;
+
+/// Arguments of `CMD_HASH_PHYS`, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HashArgs {
+    pub progress: ReadArgs,
+    /// `HASH_PER_PAGE` or `HASH_RANGE`.
+    pub mode: u32,
+    /// Set once `state` has been initialized, the caller clears it for a new range.
+    pub started: u32,
+    pub seed: u64,
+    /// Hash state of `HASH_RANGE` carried between calls.
+    pub state: Xxh64,
+    /// Receives the digest of `HASH_RANGE` once the whole range has been hashed.
+    pub digest: u64,
+}
+
+impl CommandArgs for HashArgs {}
+
+/// Arguments of `CMD_FIND_DTB`, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DtbArgs {
+    pub progress: ReadArgs,
+    /// Number of entries `dst` can hold.
+    pub max_hits: u64,
+    /// Number of candidates written to `dst`, see `ScanArgs::hits`.
+    pub hits: u64,
+    /// Receives the cr3 of the caller.
+    pub caller_cr3: u64,
+    /// Receives the kernel dtb resolved when the first command arrived.
+    pub first_kernel_dtb: u64,
+}
+
+impl CommandArgs for DtbArgs {}
+
+const IA32_EFER: u32 = 0xc0000080;
+const IA32_LSTAR: u32 = 0xc0000082;
+const IA32_GS_BASE: u32 = 0xc0000101;
+const IA32_KERNEL_GS_BASE: u32 = 0xc0000102;
+
+/// Registers of the cpu servicing a call, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuContext {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub idtr_base: u64,
+    pub gdtr_base: u64,
+    pub idtr_limit: u16,
+    pub gdtr_limit: u16,
+    pub apic_id: u32,
+    pub lstar: u64,
+    pub kernel_gs_base: u64,
+    pub gs_base: u64,
+}
+
+impl CommandArgs for CpuContext {}
+
+impl CpuContext {
+    /// Captures the registers of the current cpu, this has to be called in the caller's context.
+    pub fn capture() -> Self {
+        let (idtr, gdtr) = (sidt(), sgdt());
+        unsafe {
+            Self {
+                cr0: Cr0::read_raw(),
+                cr3: CallerCr3::read().raw(),
+                cr4: Cr4::read_raw(),
+                efer: Msr::new(IA32_EFER).read(),
+                idtr_base: { idtr.base }.as_u64(),
+                gdtr_base: { gdtr.base }.as_u64(),
+                idtr_limit: idtr.limit,
+                gdtr_limit: gdtr.limit,
+                apic_id: apic_id(),
+                lstar: Msr::new(IA32_LSTAR).read(),
+                kernel_gs_base: Msr::new(IA32_KERNEL_GS_BASE).read(),
+                gs_base: Msr::new(IA32_GS_BASE).read(),
+            }
+        }
+    }
+}
+
+/// Stores the framebuffer description in the payload.
+fn framebuffer(payload: &mut [u8]) -> efi::Status {
+    let framebuffer = framebuffer::framebuffer();
+    if framebuffer.base == 0 {
+        return efi::Status::NOT_FOUND;
+    }
+    if payload.len() < core::mem::size_of::<Framebuffer>() {
+        return efi::Status::BUFFER_TOO_SMALL;
+    }
+    framebuffer.store(payload);
+    efi::Status::SUCCESS
+}
+
+/// Stores the context of the current cpu in the payload.
+pub fn cpu_context(payload: &mut [u8]) -> efi::Status {
+    if payload.len() < core::mem::size_of::<CpuContext>() {
+        return efi::Status::BUFFER_TOO_SMALL;
+    }
+    CpuContext::capture().store(payload);
+    efi::Status::SUCCESS
+}
+
+/// Suggested `len` of `CMD_KERNEL_BASE`, the number of bytes scanned backwards from IA32_LSTAR.
+pub const KERNEL_SCAN_SIZE: usize = 0x4000000;
+
+pub const IMAGE_FORMAT_PE: u32 = 1;
+pub const IMAGE_FORMAT_ELF: u32 = 2;
+
+/// Kernel image located by `CMD_KERNEL_BASE`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelImage {
+    pub lstar: u64,
+    pub virt_base: u64,
+    pub phys_base: u64,
+    /// Size of the image, 0 if it could not be determined from the headers.
+    pub size: u64,
+    /// `IMAGE_FORMAT_PE` or `IMAGE_FORMAT_ELF`.
+    pub format: u32,
+    pub reserved: u32,
+}
+
+/// Arguments of `CMD_KERNEL_BASE`, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelBaseArgs {
+    /// The cursor counts the bytes scanned downwards from the page containing IA32_LSTAR.
+    pub progress: ReadArgs,
+    /// Receives the image once it has been found.
+    pub image: KernelImage,
+}
+
+impl CommandArgs for KernelBaseArgs {}
+
+/// Maximum number of page tables visited by a single `CMD_PAGE_MAP` call.
+pub const PAGE_MAP_MAX_TABLES: usize = 0x800;
+
+/// Arguments of `CMD_PAGE_MAP`, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PageMapArgs {
+    /// Virtual address the walk continues at, receives the address the next call has to start at.
+    pub cursor: u64,
+    /// Receives the number of entries written to `dst`.
+    pub count: u64,
+    /// Set once the end of the address space has been reached.
+    pub done: u64,
+}
+
+impl CommandArgs for PageMapArgs {}
+
+/// A run of virtually and physically contiguous pages with the same permissions.
+///
+/// `flags` holds the effective `PAGE_WRITABLE`, `PAGE_USER` and `PAGE_NX` bits.
+/// Runs are only coalesced within a single call.
+pub type PageMapEntry = PageRun;
+
+/// Arguments of `CMD_IMAGE_LIST` and `CMD_PCI_DEVICES`, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ListArgs {
+    /// Index of the first entry to copy.
+    pub index: u64,
+    /// Receives the number of entries written to `dst`.
+    pub count: u64,
+    /// Receives the number of recorded entries.
+    pub total: u64,
+}
+
+impl CommandArgs for ListArgs {}
+
+/// Result of `CMD_FIRMWARE_TABLES`, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FirmwareTablesArgs {
+    pub tables: FirmwareTables,
+    /// Receives the number of entries written to `dst`.
+    pub acpi_count: u64,
+    /// Receives the number of recorded ACPI tables.
+    pub acpi_total: u64,
+}
+
+impl CommandArgs for FirmwareTablesArgs {}
+
+impl CommandArgs for Framebuffer {}
+
+/// Maximum length of an export name including its terminator.
+pub const MAX_EXPORT_NAME_LEN: usize = 64;
+
+/// Arguments of `CMD_FIND_EXPORT`, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ExportArgs {
+    /// Zero terminated name of the export.
+    pub name: [u8; MAX_EXPORT_NAME_LEN],
+    /// Receives the physical address of the export.
+    pub address: u64,
+}
+
+impl Default for ExportArgs {
+    fn default() -> Self {
+        Self {
+            name: [0; MAX_EXPORT_NAME_LEN],
+            address: 0,
+        }
+    }
+}
+
+impl CommandArgs for ExportArgs {}
+
+/// Arguments of a command, passed in the payload following the header.
+pub trait CommandArgs: Copy + Default {
+    /// Reads the arguments from the payload, `None` is returned if the payload is too short.
+    fn parse(payload: &[u8]) -> Option<Self> {
+        if payload.len() < core::mem::size_of::<Self>() {
+            return None;
+        }
+        Some(unsafe { (payload.as_ptr() as *const Self).read_unaligned() })
+    }
+
+    /// Reads the arguments from the payload, the defaults are used for payloads without arguments.
+    fn load(payload: &[u8]) -> Self {
+        Self::parse(payload).unwrap_or_default()
+    }
+
+    /// Writes the arguments back into the payload if it contained them.
+    fn store(&self, payload: &mut [u8]) {
+        if payload.len() >= core::mem::size_of::<Self>() {
+            unsafe { (payload.as_mut_ptr() as *mut Self).write_unaligned(*self) };
+        }
+    }
+}
+
+impl MemflowCommand {
+    /// Checks the arguments of the command before any address space is switched.
+    pub fn is_valid(&self) -> bool {
+        match self.command {
+            CMD_READ_PHYS => !self.src.is_null() && !self.dst.is_null() && self.len > 0,
+            CMD_RESYNC_KERNEL => true,
+            CMD_ATOMIC_PHYS => cfg!(feature = "writes") && !self.src.is_null(),
+            // physical address 0 is a valid start of a scan
+            CMD_SCAN_PHYS => !self.dst.is_null() && self.len > 0,
+            CMD_HASH_PHYS => self.len > 0,
+            CMD_FIND_DTB => !self.dst.is_null() && self.len > 0,
+            CMD_CPU_CONTEXT => true,
+            CMD_KERNEL_BASE => self.len > 0,
+            CMD_PAGE_MAP => {
+                !self.src.is_null()
+                    && !self.dst.is_null()
+                    && self.len >= core::mem::size_of::<PageMapEntry>()
+            }
+            CMD_FIRMWARE_TABLES | CMD_FRAMEBUFFER => true,
+            CMD_FIND_EXPORT => self.len > 0,
+            CMD_IMAGE_LIST => !self.dst.is_null() && self.len >= core::mem::size_of::<ImageEntry>(),
+            CMD_PCI_DEVICES => !self.dst.is_null() && self.len >= core::mem::size_of::<PciDevice>(),
+            _ => false,
+        }
+    }
+
+    /// Returns true if the command is split into chunks of at most `CHUNK_SIZE` bytes.
+    ///
+    /// Except for reads the command advances the cursor of the `ReadArgs` leading its arguments itself.
+    pub fn is_chunked(&self) -> bool {
+        match self.command {
+            CMD_READ_PHYS | CMD_SCAN_PHYS | CMD_HASH_PHYS | CMD_FIND_DTB | CMD_KERNEL_BASE => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the part of a chunked command starting at `cursor`.
+    ///
+    /// Chunks end at `CHUNK_SIZE` boundaries of `src`.
+    /// `None` is returned if the cursor is out of range or the command exceeds the address space.
+    pub fn chunk(&self, cursor: usize) -> Option<MemflowCommand> {
+        (self.src as usize).checked_add(self.len)?;
+        let src = self.src as usize + cursor;
+        let len = (CHUNK_SIZE - src % CHUNK_SIZE).min(self.len.checked_sub(cursor)?);
+        Some(MemflowCommand {
+            src: src as *const c_void,
+            dst: (self.dst as usize).checked_add(cursor)? as *mut c_void,
+            len,
+            ..*self
+        })
+    }
+}
+
+/// Executes a command.
+///
+/// `payload` is a copy of the data following the header, it is written back to the caller afterwards.
+/// This function is called with the identity mapping active and must not allocate.
+pub fn dispatch(
+    cmd: &MemflowCommand,
+    payload: &mut [u8],
+    caller_cr3: &CallerCr3,
+    dtb: PhysFrame,
+) -> efi::Status {
+    match cmd.command {
+        CMD_READ_PHYS => read_phys(cmd, caller_cr3, dtb),
+        // the resync itself happens when entering the identity mapping
+        CMD_RESYNC_KERNEL => efi::Status::SUCCESS,
+        #[cfg(feature = "writes")]
+        CMD_ATOMIC_PHYS => atomic_phys(cmd, payload),
+        CMD_SCAN_PHYS => scan_phys(cmd, payload, caller_cr3, dtb),
+        CMD_HASH_PHYS => hash_phys(cmd, payload, caller_cr3, dtb),
+        CMD_PAGE_MAP => page_map(cmd, payload, caller_cr3, dtb),
+        CMD_FIND_DTB => find_dtb(cmd, payload, caller_cr3, dtb),
+        CMD_KERNEL_BASE => kernel_base(cmd, payload, caller_cr3),
+        CMD_IMAGE_LIST => list(cmd, payload, images::images(), caller_cr3, dtb),
+        CMD_PCI_DEVICES => list(cmd, payload, pci::pci_devices(), caller_cr3, dtb),
+        CMD_FIRMWARE_TABLES => firmware_tables(cmd, payload, caller_cr3, dtb),
+        CMD_FRAMEBUFFER => framebuffer(payload),
+        CMD_FIND_EXPORT => find_export(cmd, payload),
+        _ => efi::Status::UNSUPPORTED,
+    }
+}
+
+fn read_phys(cmd: &MemflowCommand, caller_cr3: &CallerCr3, dtb: PhysFrame) -> efi::Status {
+    let mut result = efi::Status::ACCESS_DENIED;
+
+    // Map user buffer into a free memory range
+    debug!("Identity mapping {:x}", cmd.dst as usize);
+    let identity = unsafe { &mut IDENTITY_PAGE_TABLE };
+    let mapping = identity.remap_range(cmd.dst as usize, cmd.len, caller_cr3.frame());
+
+    if let Some((_handle, remapped_dst)) = mapping {
+        debug!("Identity mapped {remapped_dst:x}");
+
+        // Fully flush TLB again now that we mapped the buffer in
+        unsafe { load_identity(dtb) };
+
+        // iterate buffer page by page
+        let mem_maps = unsafe { &EFI_MEM_MAPS };
+        let mut offs = 0usize;
+        while offs < cmd.len {
+            let addr = cmd.src as usize + offs;
+            let addr_end =
+                ((addr + 0x1000) - (addr + 0x1000) % 0x1000).min(cmd.src as usize + cmd.len);
+            let addr_align = addr - addr % 0x1000;
+            let len_align = addr_end - addr; // FB for first chunk
+
+            //trace!("Try Copy {addr_align:x}");
+
+            // check if 'src' is a valid physical memory region
+            if mem_maps.is_mapped(addr_align as u64) {
+                //trace!("Copy {:x}", addr);
+
+                // faults during the copy are caught by the private idt
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        addr as *const u8,
+                        (remapped_dst + offs) as *mut u8,
+                        len_align,
+                    )
+                };
+
+                result = efi::Status::SUCCESS;
+            } else {
+                // TODO: unneeded, buffers are 0-filled anyways
+                //unsafe { core::ptr::write_bytes((remapped_dst + offs) as *mut u8, 0, len_align) };
+            }
+
+            offs += len_align;
+        }
+    }
+
+    result
+}
+
+/// Checks if the pattern matches at `addr`.
+///
+/// The bytes following `addr` might reside in the next page, which has to be readable as well.
+fn pattern_matches(addr: u64, args: &ScanArgs, mem_maps: &EfiMemMaps) -> bool {
+    let len = args.pattern_len as usize;
+    let last = addr + len as u64 - 1;
+    if last & !0xfff != addr & !0xfff && !mem_maps.is_type_mapped(last & !0xfff, args.type_mask) {
+        return false;
+    }
+
+    // faults during the access are caught by the private idt
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    bytes
+        .iter()
+        .zip(args.pattern.iter().zip(args.mask.iter()))
+        .all(|(b, (p, m))| b & m == p & m)
+}
+
+fn scan_phys(
+    cmd: &MemflowCommand,
+    payload: &mut [u8],
+    caller_cr3: &CallerCr3,
+    dtb: PhysFrame,
+) -> efi::Status {
+    let mut args = match ScanArgs::parse(payload) {
+        Some(args) => args,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+
+    let pattern_len = args.pattern_len as usize;
+    if pattern_len == 0
+        || pattern_len > MAX_PATTERN_LEN
+        || (args.alignment != 0 && !args.alignment.is_power_of_two())
+        || args.max_hits == 0
+        || args.max_hits > (usize::MAX / 8) as u64
+    {
+        return efi::Status::INVALID_PARAMETER;
+    }
+    if args.hits >= args.max_hits {
+        return efi::Status::BUFFER_TOO_SMALL;
+    }
+
+    let identity = unsafe { &mut IDENTITY_PAGE_TABLE };
+    let mapping = identity.remap_range(
+        cmd.dst as usize,
+        args.max_hits as usize * 8,
+        caller_cr3.frame(),
+    );
+    let (_handle, hits) = match mapping {
+        Some(mapping) => mapping,
+        None => return efi::Status::NO_MAPPING,
+    };
+    unsafe { load_identity(dtb) };
+    let hits = hits as *mut u64;
+
+    let mem_maps = unsafe { &EFI_MEM_MAPS };
+    let chunk = match cmd.chunk(args.progress.cursor as usize) {
+        Some(chunk) => chunk,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+    let (start, end) = (chunk.src as u64, chunk.src as u64 + chunk.len as u64);
+    let step = args.alignment.max(1);
+
+    let mut result = efi::Status::SUCCESS;
+    let mut next = end;
+    let mut addr = (start + step - 1) & !(step - 1);
+    let mut readable_page = None;
+    while addr < end {
+        let page = addr & !0xfff;
+        if readable_page != Some(page) {
+            if !mem_maps.is_type_mapped(page, args.type_mask) {
+                // skip to the first aligned address of the next page
+                addr = (page + 0x1000 + step - 1) & !(step - 1);
+                continue;
+            }
+            readable_page = Some(page);
+        }
+
+        if pattern_matches(addr, &args, mem_maps) {
+            unsafe { hits.add(args.hits as usize).write(addr) };
+            args.hits += 1;
+            if args.hits == args.max_hits {
+                next = addr + 1;
+                result = efi::Status::BUFFER_TOO_SMALL;
+                break;
+            }
+        }
+
+        addr += step;
+    }
+
+    args.progress.cursor = next - cmd.src as u64;
+    args.store(payload);
+    result
+}
+
+/// Source for pages that cannot be read.
+static ZERO_PAGE: [u8; 0x1000] = [0; 0x1000];
+
+fn hash_phys(
+    cmd: &MemflowCommand,
+    payload: &mut [u8],
+    caller_cr3: &CallerCr3,
+    dtb: PhysFrame,
+) -> efi::Status {
+    let mut args = match HashArgs::parse(payload) {
+        Some(args) => args,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+
+    let mem_maps = unsafe { &EFI_MEM_MAPS };
+    let chunk = match cmd.chunk(args.progress.cursor as usize) {
+        Some(chunk) => chunk,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+    let (start, end) = (chunk.src as usize, chunk.src as usize + chunk.len);
+
+    match args.mode {
+        HASH_PER_PAGE => {
+            if cmd.src as usize % 0x1000 != 0 || cmd.dst.is_null() {
+                return efi::Status::INVALID_PARAMETER;
+            }
+
+            let pages = match cmd.len.checked_add(0xfff) {
+                Some(len) => len / 0x1000,
+                None => return efi::Status::INVALID_PARAMETER,
+            };
+            let identity = unsafe { &mut IDENTITY_PAGE_TABLE };
+            let mapping = identity.remap_range(cmd.dst as usize, pages * 8, caller_cr3.frame());
+            let (_handle, digests) = match mapping {
+                Some(mapping) => mapping,
+                None => return efi::Status::NO_MAPPING,
+            };
+            unsafe { load_identity(dtb) };
+            let digests = digests as *mut u64;
+
+            for addr in (start..end).step_by(0x1000) {
+                let len = (end - addr).min(0x1000);
+                // faults during the access are caught by the private idt
+                let digest = if mem_maps.is_mapped(addr as u64) {
+                    xxh64(
+                        unsafe { core::slice::from_raw_parts(addr as *const u8, len) },
+                        args.seed,
+                    )
+                } else {
+                    0
+                };
+                unsafe { digests.add((addr - cmd.src as usize) / 0x1000).write(digest) };
+            }
+        }
+        HASH_RANGE => {
+            if args.started == 0 {
+                args.state = Xxh64::new(args.seed);
+                args.started = 1;
+            } else if !args.state.is_valid() {
+                // the state is handed back by the caller
+                return efi::Status::INVALID_PARAMETER;
+            }
+
+            let mut addr = start;
+            while addr < end {
+                let len = (end - addr).min(0x1000 - addr % 0x1000);
+                let data = if mem_maps.is_mapped((addr & !0xfff) as u64) {
+                    unsafe { core::slice::from_raw_parts(addr as *const u8, len) }
+                } else {
+                    &ZERO_PAGE[..len]
+                };
+                args.state.update(data);
+                addr += len;
+            }
+
+            if end == cmd.src as usize + cmd.len {
+                args.digest = args.state.digest();
+            }
+        }
+        _ => return efi::Status::INVALID_PARAMETER,
+    }
+
+    args.progress.cursor += chunk.len as u64;
+    args.store(payload);
+    efi::Status::SUCCESS
+}
+
+fn find_dtb(
+    cmd: &MemflowCommand,
+    payload: &mut [u8],
+    caller_cr3: &CallerCr3,
+    dtb: PhysFrame,
+) -> efi::Status {
+    let mut args = match DtbArgs::parse(payload) {
+        Some(args) => args,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+
+    args.caller_cr3 = caller_cr3.raw();
+    args.first_kernel_dtb = first_kernel_dtb();
+    if args.max_hits == 0 || args.max_hits > (usize::MAX / 8) as u64 {
+        return efi::Status::INVALID_PARAMETER;
+    }
+    if args.hits >= args.max_hits {
+        args.store(payload);
+        return efi::Status::BUFFER_TOO_SMALL;
+    }
+
+    let identity = unsafe { &mut IDENTITY_PAGE_TABLE };
+    let mapping = identity.remap_range(
+        cmd.dst as usize,
+        args.max_hits as usize * 8,
+        caller_cr3.frame(),
+    );
+    let (_handle, hits) = match mapping {
+        Some(mapping) => mapping,
+        None => return efi::Status::NO_MAPPING,
+    };
+    unsafe { load_identity(dtb) };
+    let hits = hits as *mut u64;
+
+    let mem_maps = unsafe { &EFI_MEM_MAPS };
+    let chunk = match cmd.chunk(args.progress.cursor as usize) {
+        Some(chunk) => chunk,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+    let end = chunk.src as u64 + chunk.len as u64;
+
+    let mut result = efi::Status::SUCCESS;
+    let mut next = end;
+    let mut page = (chunk.src as u64 + 0xfff) & !0xfff;
+    while page < end {
+        // faults during the access are caught by the private idt
+        if mem_maps.is_mapped(page) && matches_kernel_half(page, args.first_kernel_dtb) {
+            unsafe { hits.add(args.hits as usize).write(page) };
+            args.hits += 1;
+            if args.hits == args.max_hits {
+                next = page + 1;
+                result = efi::Status::BUFFER_TOO_SMALL;
+                break;
+            }
+        }
+        page += 0x1000;
+    }
+
+    args.progress.cursor = next - cmd.src as u64;
+    args.store(payload);
+    result
+}
+
+/// Returns the format and size of the image whose first page is `page`.
+fn image_header(page: &[u8]) -> Option<(u32, u64)> {
+    if let Some(image) = PeImage::parse(page) {
+        Some((IMAGE_FORMAT_PE, image.size_of_image()? as u64))
+    } else {
+        // the size is only known if the program headers are part of the first page
+        elf::image_size(page).map(|size| (IMAGE_FORMAT_ELF, size))
+    }
+}
+
+/// Returns the kernel image whose headers are mapped at `virt`, it has to contain `lstar`.
+fn kernel_image_at(
+    virt: u64,
+    lstar: u64,
+    kernel_dtb: u64,
+    mem_maps: &EfiMemMaps,
+) -> Option<KernelImage> {
+    // discarded sections leave holes in the image
+    let phys = virt_to_phys(kernel_dtb, virt)?;
+    if !mem_maps.is_type_mapped(phys, OS_MEMORY_TYPE_MASK) {
+        return None;
+    }
+
+    // faults during the access are caught by the private idt
+    let page = unsafe { core::slice::from_raw_parts(phys as *const u8, 0x1000) };
+    let (format, size) = image_header(page)?;
+    // the image has to contain the syscall entry
+    if size != 0 && virt.saturating_add(size) <= lstar {
+        return None;
+    }
+
+    Some(KernelImage {
+        lstar,
+        virt_base: virt,
+        phys_base: phys,
+        size,
+        format,
+        reserved: 0,
+    })
+}
+
+fn kernel_base(cmd: &MemflowCommand, payload: &mut [u8], caller_cr3: &CallerCr3) -> efi::Status {
+    let mut args = match KernelBaseArgs::parse(payload) {
+        Some(args) => args,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+
+    let lstar = unsafe { Msr::new(IA32_LSTAR).read() };
+    let kernel_dtb = resolve_kernel_dtb(caller_cr3).dtb;
+    let mem_maps = unsafe { &EFI_MEM_MAPS };
+
+    let len = cmd.len as u64;
+    let mut cursor = args.progress.cursor;
+    let end = cursor.saturating_add(CHUNK_SIZE as u64).min(len);
+    let mut image = None;
+    while cursor < end {
+        let virt = match (lstar & !0xfff).checked_sub(cursor) {
+            Some(virt) => virt & !0xfff,
+            // the address space ends within the scanned range
+            None => {
+                cursor = len;
+                break;
+            }
+        };
+
+        image = kernel_image_at(virt, lstar, kernel_dtb, mem_maps);
+        if image.is_some() {
+            break;
+        }
+        cursor += 0x1000;
+    }
+
+    let result = match image {
+        Some(image) => {
+            args.image = image;
+            cursor = len;
+            efi::Status::SUCCESS
+        }
+        None if cursor >= len => efi::Status::NOT_FOUND,
+        None => efi::Status::SUCCESS,
+    };
+
+    args.progress.cursor = cursor.min(len);
+    args.store(payload);
+    result
+}
+
+/// Memory types images are loaded into, boot time images turn into OS memory after `ExitBootServices`.
+const IMAGE_MEMORY_TYPE_MASK: u64 =
+    OS_MEMORY_TYPE_MASK | 1 << efi::RUNTIME_SERVICES_CODE | 1 << efi::RUNTIME_SERVICES_DATA;
+
+fn find_export(cmd: &MemflowCommand, payload: &mut [u8]) -> efi::Status {
+    let mut args = match ExportArgs::parse(payload) {
+        Some(args) => args,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+
+    let name_len = args
+        .name
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(MAX_EXPORT_NAME_LEN);
+    let name = match core::str::from_utf8(&args.name[..name_len]) {
+        Ok(name) => name,
+        Err(_) => return efi::Status::INVALID_PARAMETER,
+    };
+
+    // the image is read through the identity mapping, it must not extend beyond its memory map
+    let mem_maps = unsafe { &EFI_MEM_MAPS };
+    let start = cmd.src as u64;
+    let contained = mem_maps.descriptor(start).is_some_and(|m| {
+        start
+            .checked_add(cmd.len as u64)
+            .is_some_and(|end| end <= m.physical_start + m.number_of_pages * 0x1000)
+    });
+    if !contained || !mem_maps.is_type_mapped(start, IMAGE_MEMORY_TYPE_MASK) {
+        return efi::Status::ACCESS_DENIED;
+    }
+
+    // faults during the access are caught by the private idt
+    match utils::find_export(cmd.src, cmd.len, name) {
+        Some(address) => {
+            args.address = address as u64;
+            args.store(payload);
+            efi::Status::SUCCESS
+        }
+        None => efi::Status::NOT_FOUND,
+    }
+}
+
+fn page_map(
+    cmd: &MemflowCommand,
+    payload: &mut [u8],
+    caller_cr3: &CallerCr3,
+    dtb: PhysFrame,
+) -> efi::Status {
+    let mut args = match PageMapArgs::parse(payload) {
+        Some(args) => args,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+
+    let capacity = cmd.len / core::mem::size_of::<PageMapEntry>();
+    let identity = unsafe { &mut IDENTITY_PAGE_TABLE };
+    let mapping = identity.remap_range(
+        cmd.dst as usize,
+        capacity * core::mem::size_of::<PageMapEntry>(),
+        caller_cr3.frame(),
+    );
+    let (_handle, entries) = match mapping {
+        Some(mapping) => mapping,
+        None => return efi::Status::ACCESS_DENIED,
+    };
+    unsafe { load_identity(dtb) };
+    let entries = entries as *mut PageMapEntry;
+
+    let mut count = 0usize;
+    let mut runs = RunCoalescer::default();
+    // start of a run that did not fit into `dst` anymore
+    let mut unwritten = None;
+    let stopped_at = walk_mapped_pages(
+        cmd.src as u64,
+        args.cursor,
+        PAGE_MAP_MAX_TABLES,
+        |virt, size, phys, flags| {
+            let run = match runs.push(virt, size, phys, flags) {
+                Some(run) => run,
+                None => return true,
+            };
+            if count == capacity {
+                unwritten = Some(run.virt);
+                return false;
+            }
+            unsafe { entries.add(count).write(run) };
+            count += 1;
+            true
+        },
+    );
+
+    // the last run is cut at the point the walk stopped
+    let next = match (unwritten, runs.open_run()) {
+        (Some(virt), _) => Some(virt),
+        (None, Some(run)) if count == capacity => Some(run.virt),
+        (None, Some(run)) => {
+            unsafe { entries.add(count).write(run) };
+            count += 1;
+            stopped_at
+        }
+        (None, None) => stopped_at,
+    };
+
+    args.count = count as u64;
+    match next {
+        Some(cursor) => args.cursor = cursor,
+        None => args.done = 1,
+    }
+    args.store(payload);
+    efi::Status::SUCCESS
+}
+
+/// Copies as many `entries` as fit into the caller buffer `dst`, returns the number of entries written.
+fn copy_entries<T: Copy>(
+    cmd: &MemflowCommand,
+    entries: &[T],
+    caller_cr3: &CallerCr3,
+    dtb: PhysFrame,
+) -> Result<usize, efi::Status> {
+    let count = (cmd.len / core::mem::size_of::<T>()).min(entries.len());
+    if count == 0 {
+        return Ok(0);
+    }
+
+    let identity = unsafe { &mut IDENTITY_PAGE_TABLE };
+    let mapping = identity.remap_range(
+        cmd.dst as usize,
+        count * core::mem::size_of::<T>(),
+        caller_cr3.frame(),
+    );
+    let (_handle, dst) = mapping.ok_or(efi::Status::ACCESS_DENIED)?;
+    unsafe { load_identity(dtb) };
+    unsafe { core::ptr::copy_nonoverlapping(entries.as_ptr(), dst as *mut T, count) };
+    Ok(count)
+}
+
+fn list<T: Copy>(
+    cmd: &MemflowCommand,
+    payload: &mut [u8],
+    entries: &[T],
+    caller_cr3: &CallerCr3,
+    dtb: PhysFrame,
+) -> efi::Status {
+    let mut args = match ListArgs::parse(payload) {
+        Some(args) => args,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+
+    let start = (args.index as usize).min(entries.len());
+    let count = match copy_entries(cmd, &entries[start..], caller_cr3, dtb) {
+        Ok(count) => count,
+        Err(status) => return status,
+    };
+
+    args.count = count as u64;
+    args.total = entries.len() as u64;
+    args.store(payload);
+    efi::Status::SUCCESS
+}
+
+fn firmware_tables(
+    cmd: &MemflowCommand,
+    payload: &mut [u8],
+    caller_cr3: &CallerCr3,
+    dtb: PhysFrame,
+) -> efi::Status {
+    if payload.len() < core::mem::size_of::<FirmwareTablesArgs>() {
+        return efi::Status::BUFFER_TOO_SMALL;
+    }
+
+    let acpi_tables = firmware_tables::acpi_tables();
+    let count = if cmd.dst.is_null() {
+        0
+    } else {
+        match copy_entries(cmd, acpi_tables, caller_cr3, dtb) {
+            Ok(count) => count,
+            Err(status) => return status,
+        }
+    };
+
+    FirmwareTablesArgs {
+        tables: firmware_tables::firmware_tables(),
+        acpi_count: count as u64,
+        acpi_total: acpi_tables.len() as u64,
+    }
+    .store(payload);
+    efi::Status::SUCCESS
+}
+
+#[cfg(feature = "writes")]
+macro_rules! atomic_op {
+    ($atomic:ty, $ty:ty, $addr:expr, $args:expr) => {{
+        let target = unsafe { &*($addr as *const $atomic) };
+        let operand = $args.operand as $ty;
+        match $args.op {
+            ATOMIC_CMPXCHG => {
+                match target.compare_exchange(
+                    $args.expected as $ty,
+                    operand,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(previous) | Err(previous) => Some(previous as u64),
+                }
+            }
+            ATOMIC_ADD => Some(target.fetch_add(operand, Ordering::SeqCst) as u64),
+            ATOMIC_OR => Some(target.fetch_or(operand, Ordering::SeqCst) as u64),
+            ATOMIC_AND => Some(target.fetch_and(operand, Ordering::SeqCst) as u64),
+            _ => None,
+        }
+    }};
+}
+
+#[cfg(feature = "writes")]
+fn atomic_phys(cmd: &MemflowCommand, payload: &mut [u8]) -> efi::Status {
+    let mut args = match AtomicArgs::parse(payload) {
+        Some(args) => args,
+        None => return efi::Status::INVALID_PARAMETER,
+    };
+
+    // unaligned atomics would split across cache lines or pages
+    let addr = cmd.src as usize;
+    if !matches!(args.size, 1 | 2 | 4 | 8) || addr % args.size as usize != 0 {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    // atomics are only performed on ram, `is_mapped` also accepts the framebuffer
+    let mem_maps = unsafe { &EFI_MEM_MAPS };
+    if !mem_maps.is_type_mapped(addr as u64, 1 << efi::CONVENTIONAL_MEMORY) {
+        return efi::Status::ACCESS_DENIED;
+    }
+
+    // faults during the access are caught by the private idt
+    let previous = match args.size {
+        1 => atomic_op!(AtomicU8, u8, addr, args),
+        2 => atomic_op!(AtomicU16, u16, addr, args),
+        4 => atomic_op!(AtomicU32, u32, addr, args),
+        _ => atomic_op!(AtomicU64, u64, addr, args),
+    };
+
+    match previous {
+        Some(previous) => {
+            args.previous = previous;
+            args.store(payload);
+            efi::Status::SUCCESS
+        }
+        None => efi::Status::INVALID_PARAMETER,
+    }
+}
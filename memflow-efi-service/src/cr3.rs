@@ -0,0 +1,297 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{
+    instructions::tlb::{flush_pcid, InvPicdCommand, Pcid},
+    registers::control::{Cr4, Cr4Flags},
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr,
+};
+
+use crate::{
+    mem_maps::OS_MEMORY_TYPE_MASK,
+    vtop::{make_bit_mask, virt_to_phys},
+    EFI_MEM_MAPS,
+};
+
+/// Bits of cr3 containing the physical address of the top level table.
+const CR3_ADDR_MASK: u64 = make_bit_mask(12, 51);
+
+/// Bits of cr3 containing the pcid when CR4.PCIDE is set.
+const CR3_PCID_MASK: u64 = 0xfff;
+
+/// Writing cr3 with this bit set keeps the TLB entries of the new pcid.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// Linux KPTI marks user pcids with this bit.
+const KPTI_USER_PCID_BIT: u64 = 1 << 11;
+
+/// Linux KPTI places the user pgd in the page directly after the kernel pgd.
+const KPTI_USER_PGD_BIT: u64 = 1 << 12;
+
+/// Shadow tables only map a handful of kernel entries, e.g. the syscall entry trampolines.
+/// A regular kernel address space maps considerably more than this.
+const SHADOW_MAX_KERNEL_ENTRIES: usize = 4;
+
+/// Number of kernel entries a candidate dtb may differ in from the kernel dtb,
+/// e.g. the session space on Windows.
+const CANDIDATE_MISMATCH_TOLERANCE: usize = 2;
+
+/// Kernel dtb resolved for the very first command.
+static FIRST_KERNEL_DTB: AtomicU64 = AtomicU64::new(0);
+
+/// The pcid used while the identity mapping is active.
+const IDENTITY_PCID: u16 = 0;
+
+/// Returns true if the OS enabled process context identifiers.
+pub fn pcid_enabled() -> bool {
+    Cr4::read().contains(Cr4Flags::PCID)
+}
+
+fn invpcid_supported() -> bool {
+    // CPUID.(EAX=07H, ECX=0H):EBX.INVPCID
+    __cpuid_count(7, 0).ebx & (1 << 10) != 0
+}
+
+/// Returns the number of physical address bits, entries must not set bits above them.
+fn max_phys_addr_bits() -> u32 {
+    // CPUID.80000008H:EAX[7:0]
+    (__cpuid(0x8000_0008).eax & 0xff).min(52)
+}
+
+/// Flushes the TLB entries of all pcids, including global ones.
+///
+/// Any write to cr4 that changes CR4.PGE does so, the bit is flipped and restored.
+unsafe fn flush_all() {
+    let cr4 = Cr4::read();
+    Cr4::write(cr4 ^ Cr4Flags::PAGE_GLOBAL);
+    Cr4::write(cr4);
+}
+
+fn read_cr3_raw() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+unsafe fn write_cr3_raw(value: u64) {
+    core::arch::asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+}
+
+/// Exact value of cr3 of the caller of a runtime service.
+///
+/// A code and a stack address in use while cr3 was read are recorded as well,
+/// any table that replaces the caller's has to map them.
+#[derive(Clone, Copy, Debug)]
+pub struct CallerCr3 {
+    raw: u64,
+    pcid_enabled: bool,
+    rip: u64,
+    rsp: u64,
+}
+
+impl CallerCr3 {
+    pub fn read() -> Self {
+        let (rip, rsp): (u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "lea {}, [rip]",
+                "mov {}, rsp",
+                out(reg) rip,
+                out(reg) rsp,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        Self {
+            raw: read_cr3_raw(),
+            pcid_enabled: pcid_enabled(),
+            rip,
+            rsp,
+        }
+    }
+
+    pub fn raw(&self) -> u64 {
+        self.raw
+    }
+
+    /// Physical address of the top level table.
+    pub fn dtb(&self) -> u64 {
+        self.raw & CR3_ADDR_MASK
+    }
+
+    pub fn frame(&self) -> PhysFrame {
+        unsafe { PhysFrame::from_start_address_unchecked(PhysAddr::new(self.dtb())) }
+    }
+
+    /// Returns the pcid of the caller if pcids are enabled.
+    pub fn pcid(&self) -> Option<u16> {
+        if self.pcid_enabled {
+            Some((self.raw & CR3_PCID_MASK) as u16)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if cr3 is known to be a Linux KPTI user table by its pcid.
+    fn is_kpti_user_pcid(&self) -> bool {
+        self.pcid_enabled && self.raw & KPTI_USER_PCID_BIT != 0
+    }
+
+    /// Returns the value to write to cr3 to restore the caller's address space.
+    ///
+    /// If the caller runs with a pcid other than the one used for the identity mapping,
+    /// its TLB entries are preserved by setting the no-flush bit.
+    pub fn restore_value(&self) -> u64 {
+        match self.pcid() {
+            Some(pcid) if pcid != IDENTITY_PCID => self.raw | CR3_NO_FLUSH,
+            _ => self.raw,
+        }
+    }
+
+    /// Drops the TLB entries of the identity mapping after `restore_value` has been written to cr3.
+    ///
+    /// Without INVPCID the entries of another pcid can only be dropped by flushing the entire TLB.
+    pub unsafe fn finish_restore(&self) {
+        match self.pcid() {
+            Some(pcid) if pcid != IDENTITY_PCID && invpcid_supported() => {
+                flush_pcid(InvPicdCommand::Single(Pcid::new(IDENTITY_PCID).unwrap()));
+            }
+            Some(pcid) if pcid != IDENTITY_PCID => flush_all(),
+            _ => (),
+        }
+    }
+}
+
+/// Loads the given identity mapping and flushes all non-global TLB entries of its pcid.
+pub unsafe fn load_identity(dtb: PhysFrame) {
+    write_cr3_raw(dtb.start_address().as_u64() | IDENTITY_PCID as u64);
+}
+
+/// Describes how the kernel dtb was obtained from the caller's cr3.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShadowTable {
+    /// cr3 already contains the full kernel half.
+    None = 0,
+    /// cr3 is a Linux KPTI user table, the kernel table has been derived from it.
+    Kpti = 1,
+    /// cr3 looks like a shadow table (e.g. Windows KVA shadow) but the kernel table is unknown.
+    Incomplete = 2,
+}
+
+/// The dtb containing the complete kernel half for a caller.
+#[derive(Clone, Copy, Debug)]
+pub struct KernelDtb {
+    pub caller_dtb: u64,
+    pub dtb: u64,
+    pub shadow: ShadowTable,
+}
+
+fn present_kernel_entries(dtb: u64) -> usize {
+    let table = unsafe { &*(dtb as *const PageTable) };
+    table
+        .iter()
+        .skip(256)
+        .filter(|e| e.flags().contains(PageTableFlags::PRESENT))
+        .count()
+}
+
+/// Remembers the kernel dtb of the first caller.
+pub fn record_kernel_dtb(kernel: &KernelDtb) {
+    let _ = FIRST_KERNEL_DTB.compare_exchange(0, kernel.dtb, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+/// Returns the kernel dtb resolved for the first command, 0 if no command has been executed yet.
+pub fn first_kernel_dtb() -> u64 {
+    FIRST_KERNEL_DTB.load(Ordering::SeqCst)
+}
+
+/// Checks if the page at `candidate` is a top level table sharing the kernel half of `kernel_dtb`.
+///
+/// Entries referencing the candidate itself (self-mapping entries) are ignored.
+/// This function has to be called while the identity mapping is active.
+pub fn matches_kernel_half(candidate: u64, kernel_dtb: u64) -> bool {
+    let table = unsafe { &*(candidate as *const PageTable) };
+    let kernel = unsafe { &*(kernel_dtb as *const PageTable) };
+
+    let mut matching = 0;
+    let mut mismatches = 0;
+    for (entry, kernel_entry) in table.iter().zip(kernel.iter()).skip(256) {
+        let present = entry.flags().contains(PageTableFlags::PRESENT);
+        if present && entry.addr().as_u64() == candidate {
+            continue;
+        }
+        if entry.addr() == kernel_entry.addr() && entry.flags() == kernel_entry.flags() {
+            if present {
+                matching += 1;
+            }
+        } else {
+            mismatches += 1;
+            if mismatches > CANDIDATE_MISMATCH_TOLERANCE {
+                return false;
+            }
+        }
+    }
+
+    // shadow tables and empty pages do not qualify
+    matching > SHADOW_MAX_KERNEL_ENTRIES
+}
+
+/// Checks if the page at `candidate` can stand in for the caller's top level table.
+///
+/// Present kernel entries have to reference RAM without setting reserved bits,
+/// and the code and stack the caller ran on have to be mapped.
+/// This function has to be called while the identity mapping is active.
+fn is_plausible_kernel_dtb(candidate: u64, caller: &CallerCr3) -> bool {
+    let mem_maps = unsafe { &EFI_MEM_MAPS };
+    let reserved = make_bit_mask(max_phys_addr_bits(), 51) | PageTableFlags::HUGE_PAGE.bits();
+    let table = unsafe { &*(candidate as *const [u64; 512]) };
+
+    let consistent = table[256..].iter().all(|&entry| {
+        entry & PageTableFlags::PRESENT.bits() == 0
+            || (entry & reserved == 0
+                && mem_maps.is_type_mapped(entry & CR3_ADDR_MASK, OS_MEMORY_TYPE_MASK))
+    });
+    consistent
+        && virt_to_phys(candidate, caller.rip).is_some()
+        && virt_to_phys(candidate, caller.rsp).is_some()
+}
+
+/// Derives the dtb with the full kernel half from the caller's cr3.
+///
+/// This function has to be called while the identity mapping is active.
+pub fn resolve_kernel_dtb(caller: &CallerCr3) -> KernelDtb {
+    let dtb = caller.dtb();
+    let entries = present_kernel_entries(dtb);
+
+    // KPTI user pgds reside in the odd page of an 8kb aligned pair.
+    // Without the user pcid the even page is only taken if it holds a proper kernel half,
+    // as it is copied into the identity mapping.
+    if dtb & KPTI_USER_PGD_BIT != 0 {
+        let kernel_dtb = dtb & !KPTI_USER_PGD_BIT;
+        if caller.is_kpti_user_pcid()
+            || (entries <= SHADOW_MAX_KERNEL_ENTRIES
+                && present_kernel_entries(kernel_dtb) > SHADOW_MAX_KERNEL_ENTRIES
+                && is_plausible_kernel_dtb(kernel_dtb, caller))
+        {
+            return KernelDtb {
+                caller_dtb: dtb,
+                dtb: kernel_dtb,
+                shadow: ShadowTable::Kpti,
+            };
+        }
+    }
+
+    let shadow = if entries <= SHADOW_MAX_KERNEL_ENTRIES {
+        ShadowTable::Incomplete
+    } else {
+        ShadowTable::None
+    };
+    KernelDtb {
+        caller_dtb: dtb,
+        dtb,
+        shadow,
+    }
+}
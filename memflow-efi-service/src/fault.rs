@@ -0,0 +1,302 @@
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::{
+    instructions::tables::{lidt, sidt},
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+
+use crate::apic;
+
+/// Exception vectors handled by the private idt.
+const VECTOR_NMI: usize = 2;
+const VECTOR_DOUBLE_FAULT: usize = 8;
+const VECTOR_SEGMENT_NOT_PRESENT: usize = 11;
+const VECTOR_GENERAL_PROTECTION: usize = 13;
+const VECTOR_PAGE_FAULT: usize = 14;
+const VECTOR_MACHINE_CHECK: usize = 18;
+
+const IDT_ENTRIES: usize = VECTOR_MACHINE_CHECK + 1;
+
+/// 64-bit interrupt gate, present, dpl 0
+const GATE_INTERRUPT: u16 = 0x8e00;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    options: u16,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl IdtEntry {
+    const fn missing() -> Self {
+        Self {
+            offset_low: 0,
+            selector: 0,
+            options: 0,
+            offset_mid: 0,
+            offset_high: 0,
+            reserved: 0,
+        }
+    }
+
+    fn new(handler: u64, selector: u16) -> Self {
+        Self {
+            offset_low: handler as u16,
+            selector,
+            options: GATE_INTERRUPT,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+static mut IDT: [IdtEntry; IDT_ENTRIES] = [IdtEntry::missing(); IDT_ENTRIES];
+
+// state shared with the assembly stubs below,
+// only the cpu holding the identity window lock installs the private idt
+static mut RECOVERY_RSP: u64 = 0;
+static mut RECOVERY_RIP: u64 = 0;
+static mut RECOVERY_CR3: u64 = 0;
+static mut FAULT_VECTOR: u64 = 0;
+static mut FAULT_ERROR_CODE: u64 = 0;
+static mut FAULT_ADDRESS: u64 = 0;
+
+static NMI_PENDING: AtomicBool = AtomicBool::new(false);
+
+// `memflow_catch` saves all callee-saved registers and the recovery state of an enclosing call
+// before calling `f(arg)` and restores them afterwards, so calls can be nested.
+// The exception stubs load the recovery cr3 (if any) and stack and jump right after the call.
+// nmis are not faults, the stub records them and returns without `iretq`,
+// so further nmis stay blocked until the OS executes its next `iretq`.
+// `memflow_catch` returns 0 if `f` returned normally and the vector + 1 otherwise.
+core::arch::global_asm!(
+    ".global memflow_catch",
+    "memflow_catch:",
+    "push rbx",
+    "push rbp",
+    "push rdi",
+    "push rsi",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "push qword ptr [rip + {recovery_rsp}]",
+    "push qword ptr [rip + {recovery_rip}]",
+    "push qword ptr [rip + {recovery_cr3}]",
+    // shadow space
+    "sub rsp, 32",
+    "mov [rip + {recovery_rsp}], rsp",
+    "lea rax, [rip + 2f]",
+    "mov [rip + {recovery_rip}], rax",
+    "mov [rip + {recovery_cr3}], r8",
+    "call rdx",
+    "xor eax, eax",
+    "2:",
+    "add rsp, 32",
+    "pop qword ptr [rip + {recovery_cr3}]",
+    "pop qword ptr [rip + {recovery_rip}]",
+    "pop qword ptr [rip + {recovery_rsp}]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rsi",
+    "pop rdi",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    ".global memflow_fault_nmi",
+    "memflow_fault_nmi:",
+    "mov byte ptr [rip + {nmi_pending}], 1",
+    "push rax",
+    "push rcx",
+    "push rdx",
+    // rip, rflags and rax are put onto the interrupted stack, below the interrupted rsp
+    "mov rax, [rsp + 48]",
+    "mov rcx, [rsp + 24]",
+    "mov rdx, [rsp + 40]",
+    "mov [rax - 8], rcx",
+    "mov [rax - 16], rdx",
+    "mov rcx, [rsp + 16]",
+    "mov [rax - 24], rcx",
+    "mov rcx, [rsp + 8]",
+    "mov rdx, [rsp]",
+    "lea rsp, [rax - 24]",
+    "pop rax",
+    "popfq",
+    "ret",
+    // exceptions without an error code push a dummy one
+    ".global memflow_fault_df",
+    "memflow_fault_df:",
+    "push 8",
+    "jmp memflow_fault_common",
+    ".global memflow_fault_np",
+    "memflow_fault_np:",
+    "push 11",
+    "jmp memflow_fault_common",
+    ".global memflow_fault_gp",
+    "memflow_fault_gp:",
+    "push 13",
+    "jmp memflow_fault_common",
+    ".global memflow_fault_pf",
+    "memflow_fault_pf:",
+    "push 14",
+    "jmp memflow_fault_common",
+    ".global memflow_fault_mc",
+    "memflow_fault_mc:",
+    "push 0",
+    "push 18",
+    "jmp memflow_fault_common",
+    "memflow_fault_common:",
+    "pop rax",
+    "mov [rip + {fault_vector}], rax",
+    "pop rax",
+    "mov [rip + {fault_error_code}], rax",
+    "mov rax, cr2",
+    "mov [rip + {fault_address}], rax",
+    // resume in memflow_catch with its address space and stack
+    "mov rax, [rip + {recovery_cr3}]",
+    "test rax, rax",
+    "jz 3f",
+    "mov cr3, rax",
+    "3:",
+    "mov rsp, [rip + {recovery_rsp}]",
+    "mov rax, [rip + {fault_vector}]",
+    "inc rax",
+    "jmp qword ptr [rip + {recovery_rip}]",
+    recovery_rsp = sym RECOVERY_RSP,
+    recovery_rip = sym RECOVERY_RIP,
+    recovery_cr3 = sym RECOVERY_CR3,
+    fault_vector = sym FAULT_VECTOR,
+    fault_error_code = sym FAULT_ERROR_CODE,
+    fault_address = sym FAULT_ADDRESS,
+    nmi_pending = sym NMI_PENDING,
+);
+
+extern "C" {
+    fn memflow_catch(arg: *mut c_void, f: extern "C" fn(*mut c_void), cr3: u64) -> u64;
+    fn memflow_fault_nmi();
+    fn memflow_fault_df();
+    fn memflow_fault_np();
+    fn memflow_fault_gp();
+    fn memflow_fault_pf();
+    fn memflow_fault_mc();
+}
+
+/// An exception that occurred while the private idt was active.
+#[derive(Clone, Copy, Debug)]
+pub struct Fault {
+    pub vector: u64,
+    pub error_code: u64,
+    /// Value of cr2, only meaningful for page faults.
+    pub address: u64,
+}
+
+/// Sends an nmi taken while the private idt was active to the current cpu again.
+///
+/// nmis stay blocked after the stub returned, so the nmi is held pending
+/// until the OS executes its next `iretq` with its own idt in place.
+/// If the apic cannot be reached the nmi stays recorded for a later call.
+pub fn reraise_nmi() {
+    if NMI_PENDING.load(Ordering::SeqCst) && apic::send_self_nmi().is_ok() {
+        NMI_PENDING.store(false, Ordering::SeqCst);
+    }
+}
+
+unsafe fn install_idt() -> DescriptorTablePointer {
+    let prev_idt = sidt();
+
+    let selector: u16;
+    core::arch::asm!("mov {0:x}, cs", out(reg) selector, options(nomem, nostack, preserves_flags));
+
+    let handlers: [(usize, unsafe extern "C" fn()); 6] = [
+        (VECTOR_NMI, memflow_fault_nmi),
+        (VECTOR_DOUBLE_FAULT, memflow_fault_df),
+        (VECTOR_SEGMENT_NOT_PRESENT, memflow_fault_np),
+        (VECTOR_GENERAL_PROTECTION, memflow_fault_gp),
+        (VECTOR_PAGE_FAULT, memflow_fault_pf),
+        (VECTOR_MACHINE_CHECK, memflow_fault_mc),
+    ];
+    for (vector, handler) in handlers.iter() {
+        IDT[*vector] = IdtEntry::new(*handler as usize as u64, selector);
+    }
+
+    lidt(&DescriptorTablePointer {
+        limit: (core::mem::size_of_val(&IDT) - 1) as u16,
+        base: VirtAddr::new(IDT.as_ptr() as u64),
+    });
+
+    prev_idt
+}
+
+struct CatchData<F, R> {
+    f: Option<F>,
+    result: Option<R>,
+}
+
+extern "C" fn catch_trampoline<F: FnOnce() -> R, R>(data: *mut c_void) {
+    let data = unsafe { &mut *(data as *mut CatchData<F, R>) };
+    if let Some(f) = data.f.take() {
+        data.result = Some(f());
+    }
+}
+
+/// Runs `f` with a private idt installed.
+///
+/// Page faults, general protection faults and machine checks raised by `f` abort it
+/// and return the fault instead of being delivered to the OS, whose handlers
+/// would run in the wrong address space. nmis are re-raised once the outermost call
+/// restored the previous idt, see `reraise_nmi`.
+/// Destructors of values owned by `f` are not run when it is aborted.
+///
+/// Calls may be nested, a fault aborts the innermost one.
+/// This function has to be called with interrupts disabled and by a single cpu at a time.
+pub fn catch_faults<F: FnOnce() -> R, R>(f: F) -> Result<R, Fault> {
+    catch_faults_with_cr3(0, f)
+}
+
+/// Runs `f` like `catch_faults`, cr3 is set to `cr3` before returning a fault.
+///
+/// This allows `f` to switch to another address space, the idt and its handlers
+/// have to be mapped at the same address in both of them.
+pub fn catch_faults_with_cr3<F: FnOnce() -> R, R>(cr3: u64, f: F) -> Result<R, Fault> {
+    let mut data = CatchData {
+        f: Some(f),
+        result: None,
+    };
+
+    let prev_idt = unsafe { install_idt() };
+    let ret = unsafe {
+        memflow_catch(
+            &mut data as *mut _ as *mut c_void,
+            catch_trampoline::<F, R>,
+            cr3,
+        )
+    };
+    unsafe { lidt(&prev_idt) };
+    if { prev_idt.base }.as_u64() != unsafe { core::ptr::addr_of!(IDT) } as u64 {
+        reraise_nmi();
+    }
+
+    if ret == 0 {
+        // the trampoline always stores a result when it returns normally
+        Ok(data.result.take().unwrap())
+    } else {
+        let fault = unsafe {
+            Fault {
+                vector: FAULT_VECTOR,
+                error_code: FAULT_ERROR_CODE,
+                address: FAULT_ADDRESS,
+            }
+        };
+        debug_assert_eq!(ret, fault.vector + 1);
+        Err(fault)
+    }
+}
@@ -0,0 +1,200 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use ::r_efi::*;
+
+use crate::system_table;
+
+/// EFI_ACPI_20_TABLE_GUID, not part of r-efi.
+const ACPI_20_TABLE_GUID: efi::Guid = efi::Guid::from_fields(
+    0x8868e871,
+    0xe4f1,
+    0x11d3,
+    0xbc,
+    0x22,
+    &[0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+);
+
+/// ACPI_TABLE_GUID of ACPI 1.0 firmware, not part of r-efi.
+const ACPI_10_TABLE_GUID: efi::Guid = efi::Guid::from_fields(
+    0xeb9d2d30,
+    0x2d88,
+    0x11d3,
+    0x9a,
+    0x16,
+    &[0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+);
+
+/// SMBIOS3_TABLE_GUID, not part of r-efi.
+const SMBIOS3_TABLE_GUID: efi::Guid = efi::Guid::from_fields(
+    0xf2fd1544,
+    0x9794,
+    0x4a2c,
+    0x99,
+    0x2e,
+    &[0xe5, 0xbb, 0xcf, 0x20, 0xe3, 0x94],
+);
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const SMBIOS3_ANCHOR: &[u8; 5] = b"_SM3_";
+
+/// Size of the common header of all ACPI system description tables.
+const ACPI_HEADER_SIZE: usize = 36;
+
+/// Maximum number of ACPI tables that are recorded.
+pub const MAX_ACPI_TABLES: usize = 64;
+
+/// Physical addresses of the firmware tables, 0 if a table was not found.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FirmwareTables {
+    pub rsdp: u64,
+    pub xsdt: u64,
+    pub rsdt: u64,
+    pub smbios3: u64,
+    /// Structure table referenced by the SMBIOS 3 entry point.
+    pub smbios_table: u64,
+    /// Maximum size of the SMBIOS structure table.
+    pub smbios_table_size: u64,
+}
+
+/// An ACPI table referenced by the XSDT, or the RSDT if the firmware only provides the latter.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AcpiTableEntry {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub address: u64,
+}
+
+// written once while boot services are available and read-only afterwards
+static mut FIRMWARE_TABLES: FirmwareTables = FirmwareTables {
+    rsdp: 0,
+    xsdt: 0,
+    rsdt: 0,
+    smbios3: 0,
+    smbios_table: 0,
+    smbios_table_size: 0,
+};
+static mut ACPI_TABLES: [AcpiTableEntry; MAX_ACPI_TABLES] = [AcpiTableEntry {
+    signature: [0; 4],
+    length: 0,
+    address: 0,
+}; MAX_ACPI_TABLES];
+static ACPI_TABLE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+unsafe fn read<T: Copy>(addr: u64) -> T {
+    (addr as *const T).read_unaligned()
+}
+
+/// Locates the ACPI and SMBIOS 3 entry points in the configuration table of the system table.
+///
+/// Boot services memory is identity mapped at this point, so the tables are read through their physical addresses.
+pub fn init() -> Result<(), &'static str> {
+    let st = system_table();
+    if st.configuration_table.is_null() {
+        return Err("system table has no configuration tables");
+    }
+    let entries =
+        unsafe { core::slice::from_raw_parts(st.configuration_table, st.number_of_table_entries) };
+
+    let tables = unsafe { &mut FIRMWARE_TABLES };
+    let mut acpi_10_rsdp = 0;
+    for entry in entries.iter() {
+        let addr = entry.vendor_table as u64;
+        if entry.vendor_guid == ACPI_20_TABLE_GUID {
+            tables.rsdp = addr;
+        } else if entry.vendor_guid == ACPI_10_TABLE_GUID {
+            acpi_10_rsdp = addr;
+        } else if entry.vendor_guid == SMBIOS3_TABLE_GUID {
+            unsafe { record_smbios3(tables, addr) };
+        }
+    }
+    if tables.rsdp == 0 {
+        tables.rsdp = acpi_10_rsdp;
+    }
+
+    if tables.rsdp != 0 {
+        unsafe { record_acpi(tables) };
+    }
+
+    info!(
+        "firmware tables: rsdp={:x} xsdt={:x} rsdt={:x} smbios3={:x}, {} acpi tables",
+        tables.rsdp,
+        tables.xsdt,
+        tables.rsdt,
+        tables.smbios3,
+        acpi_table_count()
+    );
+    Ok(())
+}
+
+unsafe fn record_smbios3(tables: &mut FirmwareTables, entry_point: u64) {
+    if read::<[u8; 5]>(entry_point) != *SMBIOS3_ANCHOR {
+        warn!("invalid smbios3 entry point at {:x}", entry_point);
+        return;
+    }
+    tables.smbios3 = entry_point;
+    tables.smbios_table_size = read::<u32>(entry_point + 0x0c) as u64;
+    tables.smbios_table = read::<u64>(entry_point + 0x10);
+}
+
+unsafe fn record_acpi(tables: &mut FirmwareTables) {
+    let rsdp = tables.rsdp;
+    if read::<[u8; 8]>(rsdp) != *RSDP_SIGNATURE {
+        warn!("invalid rsdp at {:x}", rsdp);
+        return;
+    }
+
+    // the xsdt is only present from revision 2 on
+    tables.rsdt = read::<u32>(rsdp + 16) as u64;
+    if read::<u8>(rsdp + 15) >= 2 {
+        tables.xsdt = read::<u64>(rsdp + 24);
+    }
+
+    let (sdt, entry_size) = match (tables.xsdt, tables.rsdt) {
+        (0, 0) => return,
+        (0, rsdt) => (rsdt, 4),
+        (xsdt, _) => (xsdt, 8),
+    };
+    let length = read::<u32>(sdt + 4) as usize;
+    let count = (length.saturating_sub(ACPI_HEADER_SIZE) / entry_size).min(MAX_ACPI_TABLES);
+
+    let acpi_tables = &mut ACPI_TABLES;
+    let mut recorded = 0;
+    for i in 0..count {
+        let entry = sdt + (ACPI_HEADER_SIZE + i * entry_size) as u64;
+        let address = match entry_size {
+            8 => read::<u64>(entry),
+            _ => read::<u32>(entry) as u64,
+        };
+        if address == 0 {
+            continue;
+        }
+        acpi_tables[recorded] = AcpiTableEntry {
+            signature: read::<[u8; 4]>(address),
+            length: read::<u32>(address + 4),
+            address,
+        };
+        recorded += 1;
+    }
+    ACPI_TABLE_COUNT.store(recorded, Ordering::SeqCst);
+}
+
+/// Forgets the located tables, used when unloading the driver.
+pub fn reset() {
+    ACPI_TABLE_COUNT.store(0, Ordering::SeqCst);
+    unsafe { FIRMWARE_TABLES = FirmwareTables::default() };
+}
+
+pub fn firmware_tables() -> FirmwareTables {
+    unsafe { FIRMWARE_TABLES }
+}
+
+pub fn acpi_table_count() -> usize {
+    ACPI_TABLE_COUNT.load(Ordering::SeqCst)
+}
+
+/// Returns the recorded ACPI tables.
+pub fn acpi_tables() -> &'static [AcpiTableEntry] {
+    unsafe { &ACPI_TABLES[..acpi_table_count()] }
+}
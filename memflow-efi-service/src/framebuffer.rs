@@ -0,0 +1,118 @@
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use ::r_efi::*;
+use r_efi::protocols::graphics_output;
+
+use crate::{boot_services, identity_page_table::IdentityPageTable};
+
+/// Framebuffer of the graphics output protocol, following the header in the SetVariable data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Framebuffer {
+    /// Physical base of the framebuffer, 0 if no linear framebuffer is available.
+    pub base: u64,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per scan line, which might exceed `width`.
+    pub stride: u32,
+    /// One of the `PIXEL_*` formats of the graphics output protocol.
+    pub pixel_format: u32,
+    /// Masks of the color channels, only valid for `PIXEL_BIT_MASK`.
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub reserved_mask: u32,
+}
+
+// written once while boot services are available and read-only afterwards
+static mut FRAMEBUFFER: Framebuffer = Framebuffer {
+    base: 0,
+    size: 0,
+    width: 0,
+    height: 0,
+    stride: 0,
+    pixel_format: 0,
+    red_mask: 0,
+    green_mask: 0,
+    blue_mask: 0,
+    reserved_mask: 0,
+};
+
+/// Set once the framebuffer is part of the identity mapping.
+static FRAMEBUFFER_MAPPED: AtomicBool = AtomicBool::new(false);
+
+/// Records the framebuffer of the current graphics mode and maps it into the identity mapping.
+///
+/// The framebuffer usually lies in a pci bar, it is mapped uncached so it can be read like device memory.
+/// This has to be called while boot services are available.
+pub fn init(identity_page_table: &mut IdentityPageTable) -> Result<(), &'static str> {
+    let mut gop: *mut graphics_output::Protocol = core::ptr::null_mut();
+    let status = (boot_services().locate_protocol)(
+        &mut graphics_output::PROTOCOL_GUID as *mut _,
+        core::ptr::null_mut(),
+        &mut gop as *mut _ as *mut *mut c_void,
+    );
+    if status.is_error() || gop.is_null() {
+        return Err("graphics output protocol is not available");
+    }
+
+    let mode = unsafe { (*gop).mode };
+    if mode.is_null() || unsafe { (*mode).info.is_null() } {
+        return Err("graphics output protocol has no mode");
+    }
+    let (mode, info) = unsafe { (&*mode, &*(*mode).info) };
+    if info.pixel_format == graphics_output::PIXEL_BLT_ONLY || mode.frame_buffer_base == 0 {
+        return Err("graphics mode has no linear framebuffer");
+    }
+
+    let framebuffer = Framebuffer {
+        base: mode.frame_buffer_base,
+        size: mode.frame_buffer_size as u64,
+        width: info.horizontal_resolution,
+        height: info.vertical_resolution,
+        stride: info.pixels_per_scan_line,
+        pixel_format: info.pixel_format,
+        red_mask: info.pixel_information.red_mask,
+        green_mask: info.pixel_information.green_mask,
+        blue_mask: info.pixel_information.blue_mask,
+        reserved_mask: info.pixel_information.reserved_mask,
+    };
+    unsafe { FRAMEBUFFER = framebuffer };
+
+    identity_page_table.map_mmio(framebuffer.base, framebuffer.size)?;
+    FRAMEBUFFER_MAPPED.store(true, Ordering::SeqCst);
+
+    info!(
+        "framebuffer at {:x} ({:x} bytes), {}x{} stride {} format {}",
+        framebuffer.base,
+        framebuffer.size,
+        framebuffer.width,
+        framebuffer.height,
+        framebuffer.stride,
+        framebuffer.pixel_format
+    );
+    Ok(())
+}
+
+/// Forgets the framebuffer, used when the identity mapping is released.
+pub fn reset() {
+    FRAMEBUFFER_MAPPED.store(false, Ordering::SeqCst);
+    unsafe {
+        FRAMEBUFFER.base = 0;
+        FRAMEBUFFER.size = 0;
+    }
+}
+
+pub fn framebuffer() -> Framebuffer {
+    unsafe { FRAMEBUFFER }
+}
+
+/// Checks if `addr` lies in the framebuffer, which can be read despite being device memory.
+pub fn is_framebuffer(addr: u64) -> bool {
+    let framebuffer = framebuffer();
+    FRAMEBUFFER_MAPPED.load(Ordering::SeqCst)
+        && framebuffer.base <= addr
+        && addr - framebuffer.base < framebuffer.size
+}
@@ -1,15 +1,25 @@
-use core::{convert::identity, ffi::c_void};
+use core::{
+    arch::x86_64::_rdtsc,
+    ffi::c_void,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use ::r_efi::{system::*, *};
-use x86_64::{
-    registers::control::{Cr3, Cr3Flags},
-    structures::paging::{PhysFrame, Size4KiB},
-    PhysAddr,
-};
+use x86_64::structures::paging::PhysFrame;
 
 use crate::{
-    runtime_services, runtime_services_mut, utils::hook_service_pointer, vtop::virt_to_phys,
-    EFI_MEM_MAPS, IDENTITY_CR3, IDENTITY_PAGE_TABLE, IDENTITY_PAGE_TABLE_BASE,
+    commands::{
+        self, CommandArgs, MemflowCommand, ReadArgs, CMD_CPU_CONTEXT, CMD_READ_PHYS,
+        CMD_RESYNC_KERNEL, MAX_PAYLOAD_SIZE, MEMFLOW_MAGIC,
+    },
+    cr3::{load_identity, record_kernel_dtb, resolve_kernel_dtb, CallerCr3, KernelDtb},
+    fault::{self, catch_faults, catch_faults_with_cr3},
+    logger,
+    percpu::{self, MAX_CPUS},
+    runtime_services, runtime_services_mut,
+    utils::{hook_service_pointer, Mutex},
+    IDENTITY_PAGE_TABLE,
 };
 
 pub unsafe fn init_hooks() {
@@ -24,6 +34,27 @@ pub unsafe fn init_hooks() {
     );
 }
 
+/// Restores the original runtime services, used when the driver is unloaded.
+///
+/// Commands still running on other cpus are waited for, new calls go straight to the firmware.
+pub unsafe fn remove_hooks() {
+    if !ORIG_SET_VARIABLE.is_null() {
+        hook_service_pointer(
+            &mut runtime_services_mut().set_variable as *mut _ as *mut *mut _,
+            ORIG_SET_VARIABLE as *mut _,
+        );
+    }
+    if !ORIG_GET_TIME.is_null() {
+        hook_service_pointer(
+            &mut runtime_services_mut().get_time as *mut _ as *mut *mut _,
+            ORIG_GET_TIME as *mut _,
+        );
+    }
+
+    // wait for a command that entered before the pointers were restored
+    let _lock = WINDOW_LOCK.lock();
+}
+
 pub unsafe fn convert_hook_pointers() {
     let prev_set_variable = &mut ORIG_SET_VARIABLE as *mut *const _ as usize;
     (runtime_services().convert_pointer)(0, &mut ORIG_SET_VARIABLE as *mut *const _ as *mut *mut _);
@@ -40,18 +71,313 @@ pub unsafe fn convert_hook_pointers() {
     );
 }
 
-#[repr(C)]
-pub struct MemflowCommand {
-    magic: u32,
-    src: *const c_void,
-    dst: *mut c_void,
-    len: usize,
+/// Number of times the kernel half of the identity mapping has been synchronized with a caller.
+static KERNEL_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Kernel dtb derived from the most recent caller.
+static mut KERNEL_DTB: Option<KernelDtb> = None;
+
+/// Serializes the identity window between cpus.
+///
+/// The identity mapping, its remap slots and the fault recovery state are shared,
+/// so only a single cpu may execute a command at a time.
+/// Chunked commands drop the lock between chunks.
+/// The boot time protocol takes it as well, as it shares the fault recovery state.
+pub static WINDOW_LOCK: Mutex<()> = Mutex::new(());
+
+/// State of a command handed to the identity window.
+///
+/// The context lives in our image instead of on the caller's stack,
+/// as the image stays mapped in the identity mapping even if the caller's kernel half is incomplete.
+#[derive(Clone, Copy)]
+struct WindowContext {
+    cmd: MemflowCommand,
+    caller_cr3: CallerCr3,
+    dtb: PhysFrame,
+    // table the kernel half is synchronized with
+    src: u64,
+    // the caller has already been resolved to its kernel dtb
+    known: bool,
+    // copy of the caller's payload, the caller's memory might not be mapped in the identity mapping
+    payload: [u8; MAX_PAYLOAD_SIZE],
+    payload_len: usize,
+    result: efi::Status,
+}
+
+static mut WINDOW_CONTEXTS: [MaybeUninit<WindowContext>; MAX_CPUS] =
+    [MaybeUninit::uninit(); MAX_CPUS];
+
+/// Switches to the identity mapping and the per-cpu stack and runs `window_entry` there.
+///
+/// The kernel half is compared against `src` on every call and copied again
+/// whenever an entry changed (e.g. new top level entries, a different session space or a new OS after kexec).
+/// The pinned entry put back in place of a missing kernel entry does not count as a change.
+/// The stack is switched together with cr3 so the caller's stack is never touched inside the identity mapping.
+unsafe fn run_identity_window(
+    ctx: *mut WindowContext,
+    dtb: PhysFrame,
+    src: u64,
+    force: bool,
+    stack_top: u64,
+) {
+    // the pinned entry of the top level table, i.e. of the pml5 with 5-level paging
+    let (pinned_offset, pinned_entry) = match IDENTITY_PAGE_TABLE.pinned_entry() {
+        Some((index, entry)) => (index as u64 * 8, entry),
+        None => (0, 0),
+    };
+
+    core::arch::asm!(
+        "mov r12, rsp",
+        // Write new dtb
+        "mov cr3, rdi",
+        // Switch to our own stack
+        "mov rsp, r13",
+        "mov rax, rdi",
+        // Compare kernel entries with our mapping
+        "add rdi, 2048",
+        "add rsi, 2048",
+        "test rdx, rdx",
+        "jnz 3f",
+        "6:",
+        "repe cmpsq",
+        "je 5f",
+        // Our image mapping differs from a kernel half that lacks it, skip it
+        "test r14, r14",
+        "jz 7f",
+        "lea r10, [rax + r14 + 8]",
+        "cmp rdi, r10",
+        "jne 7f",
+        "test byte ptr [rsi - 8], 1",
+        "jnz 7f",
+        "cmp [rdi - 8], r15",
+        "jne 7f",
+        "test rcx, rcx",
+        "jnz 6b",
+        "jmp 5f",
+        "7:",
+        // Step back to the first entry that differs
+        "sub rdi, 8",
+        "sub rsi, 8",
+        "inc rcx",
+        // Copy kernel pages to our mapping
+        "3:",
+        "rep movsq",
+        "mov rdx, 1",
+        // Put our image mapping back if the copied kernel half lacks it
+        "test r14, r14",
+        "jz 4f",
+        "test byte ptr [rax + r14], 1",
+        "jnz 4f",
+        "mov [rax + r14], r15",
+        // Flush TLB
+        "4:",
+        "mov cr3, rax",
+        "5:",
+        // window_entry(ctx, changed) returns the value to restore cr3 with
+        "mov rcx, r8",
+        "sub rsp, 32",
+        "call r9",
+        "mov cr3, rax",
+        "mov rsp, r12",
+        // Explicit registers because cmpsq/movsq operate on rsi and rdi
+        inout("rdi") dtb.start_address().as_u64() => _,
+        // These registers may be clobbered upon copy
+        inout("rsi") src => _,
+        inout("rcx") 256usize => _,
+        inout("rdx") force as u64 => _,
+        in("r8") ctx,
+        in("r9") window_entry as *const () as usize,
+        out("r12") _,
+        in("r13") stack_top,
+        in("r14") pinned_offset,
+        in("r15") pinned_entry,
+        clobber_abi("C"),
+    );
+}
+
+/// Runs a command inside the identity mapping on the per-cpu stack.
+///
+/// Returns the value that has to be written to cr3 to restore the caller's address space.
+extern "C" fn window_entry(ctx: *mut WindowContext, changed: u64) -> u64 {
+    let ctx = unsafe { &mut *ctx };
+
+    if changed != 0 {
+        let generation = KERNEL_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+        debug!("kernel half synchronized from {:x}; generation={generation}", ctx.src);
+    }
+
+    if !ctx.known || changed != 0 {
+        // the caller might run on a shadow table with an incomplete kernel half,
+        // the kernel half copied above is sufficient to keep running until we replaced it.
+        let kernel_dtb = resolve_kernel_dtb(&ctx.caller_cr3);
+        debug!("caller cr3: {:x}; kernel dtb: {:x?}", ctx.caller_cr3.raw(), kernel_dtb);
+        if kernel_dtb.dtb != ctx.src {
+            unsafe { IDENTITY_PAGE_TABLE.copy_pml4_entries(kernel_dtb.dtb).ok() };
+            unsafe { load_identity(ctx.dtb) };
+        }
+        record_kernel_dtb(&kernel_dtb);
+        unsafe { KERNEL_DTB = Some(kernel_dtb) };
+    }
+
+    // faults inside the identity mapping abort the command instead of reaching the OS
+    let (cmd, caller_cr3, dtb) = (&ctx.cmd, &ctx.caller_cr3, ctx.dtb);
+    let payload = &mut ctx.payload[..ctx.payload_len];
+    ctx.result = match catch_faults(|| commands::dispatch(cmd, payload, caller_cr3, dtb)) {
+        Ok(result) => result,
+        Err(fault) => {
+            // the aborted command might have held the log lock
+            logger::release_aborted();
+            error!("command {} aborted: {:x?}", cmd.command, fault);
+            // remappings of the aborted command are never released
+            unsafe { IDENTITY_PAGE_TABLE.reset_remaps() };
+            efi::Status::ABORTED
+        }
+    };
+
+    // the xapic is only reachable inside the identity mapping
+    fault::reraise_nmi();
+
+    ctx.caller_cr3.restore_value()
+}
+
+fn handle_command(cmd: &MemflowCommand, payload: &mut [u8]) -> efi::Status {
+    if !cmd.is_valid() {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    let (cpu, stack_top) = match percpu::cpu_index()
+        .and_then(|cpu| percpu::stack_top(cpu).map(|stack_top| (cpu, stack_top)))
+    {
+        Some(slot) => slot,
+        None => return efi::Status::OUT_OF_RESOURCES,
+    };
+
+    // the context of the caller is lost once the identity mapping is entered
+    if cmd.command == CMD_CPU_CONTEXT {
+        return commands::cpu_context(payload);
+    }
+
+    if !cmd.is_chunked() {
+        return run_command(cmd, payload, cpu, stack_top);
+    }
+
+    // commands other than reads require their arguments to track the cursor
+    if cmd.command != CMD_READ_PHYS && ReadArgs::parse(payload).is_none() {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    let mut args = ReadArgs::load(payload);
+    if args.cursor > cmd.len as u64 {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    // interrupts are only disabled for the duration of a single chunk
+    let start = unsafe { _rdtsc() };
+    let mut cursor = args.cursor as usize;
+    let mut result = efi::Status::ACCESS_DENIED;
+    let status = loop {
+        if cursor >= cmd.len {
+            break result;
+        }
+
+        let status = if cmd.command == CMD_READ_PHYS {
+            let chunk = match cmd.chunk(cursor) {
+                Some(chunk) => chunk,
+                None => break efi::Status::INVALID_PARAMETER,
+            };
+            cursor += chunk.len;
+            run_command(&chunk, &mut [], cpu, stack_top)
+        } else {
+            // the command processes the chunk at the cursor and advances it
+            args.cursor = cursor as u64;
+            args.store(payload);
+            let status = run_command(cmd, payload, cpu, stack_top);
+            cursor = ReadArgs::load(payload).cursor as usize;
+            status
+        };
+
+        match status {
+            efi::Status::SUCCESS => result = efi::Status::SUCCESS,
+            // chunks of a read without readable memory are skipped,
+            // the other commands advance the cursor on success only
+            efi::Status::ACCESS_DENIED if cmd.command == CMD_READ_PHYS => (),
+            status => break status,
+        }
+
+        if cursor < cmd.len
+            && args.budget != 0
+            && unsafe { _rdtsc() }.wrapping_sub(start) >= args.budget
+        {
+            break efi::Status::TIMEOUT;
+        }
+    };
+
+    args.cursor = cursor as u64;
+    args.store(payload);
+    status
 }
-const _: [(); core::mem::size_of::<MemflowCommand>()] = [(); 32];
 
-static mut KERNEL_MAPPED: u8 = 0;
+/// Executes a single command (or chunk) inside the identity mapping with interrupts disabled.
+///
+/// Up to `MAX_PAYLOAD_SIZE` bytes of the payload are passed to the command and copied back afterwards.
+fn run_command(
+    cmd: &MemflowCommand,
+    payload: &mut [u8],
+    cpu: usize,
+    stack_top: u64,
+) -> efi::Status {
+    // the exact value is required to restore pcid and flush behavior
+    let caller_cr3 = CallerCr3::read();
+
+    // the paging mode is chosen by the OS, so the top level table is selected on each call
+    let dtb = unsafe { IDENTITY_PAGE_TABLE.dtb() };
+
+    {
+        // interrupts stay disabled until the lock is released
+        let _lock = WINDOW_LOCK.lock();
+
+        // use the full kernel table if the caller is known to run on a shadow table
+        let known = unsafe { KERNEL_DTB }.filter(|k| k.caller_dtb == caller_cr3.dtb());
+        let src = known.map(|k| k.dtb).unwrap_or_else(|| caller_cr3.dtb());
+        let force =
+            cmd.command == CMD_RESYNC_KERNEL || KERNEL_GENERATION.load(Ordering::SeqCst) == 0;
+
+        let payload_len = payload.len().min(MAX_PAYLOAD_SIZE);
+        let ctx = unsafe {
+            WINDOW_CONTEXTS[cpu].as_mut_ptr().write(WindowContext {
+                cmd: *cmd,
+                caller_cr3,
+                dtb,
+                src,
+                known: known.is_some(),
+                payload: [0; MAX_PAYLOAD_SIZE],
+                payload_len,
+                result: efi::Status::ABORTED,
+            });
+            WINDOW_CONTEXTS[cpu].as_mut_ptr()
+        };
+
+        unsafe {
+            (&mut (*ctx).payload)[..payload_len].copy_from_slice(&payload[..payload_len]);
 
-static mut VAR_CALLED: usize = 0;
+            // faults while synchronizing the kernel half or resolving the kernel dtb
+            // return here with the caller's address space restored
+            let window = catch_faults_with_cr3(caller_cr3.restore_value(), || {
+                run_identity_window(ctx, dtb, src, force, stack_top)
+            });
+            caller_cr3.finish_restore();
+            if let Err(fault) = window {
+                logger::release_aborted();
+                error!("identity window aborted: {:x?}", fault);
+                IDENTITY_PAGE_TABLE.reset_remaps();
+                (*ctx).result = efi::Status::ABORTED;
+            }
+
+            payload[..payload_len].copy_from_slice(&(&(*ctx).payload)[..payload_len]);
+            (*ctx).result
+        }
+    }
+}
 
 static mut ORIG_SET_VARIABLE: *const c_void = core::ptr::null_mut();
 eficall! {fn hook_set_variable(
@@ -61,8 +387,6 @@ eficall! {fn hook_set_variable(
     data_size: usize,
     data: *mut c_void,
 ) -> crate::base::Status {
-    let var_called = unsafe { &mut VAR_CALLED };
-    *var_called += 1;
     //info!("hook_set_variable called: orig={:x} cnt={var_called}", unsafe { ORIG_SET_VARIABLE as u64 });
     //
 
@@ -72,96 +396,20 @@ eficall! {fn hook_set_variable(
         let guid = unsafe { &*vendor_guid };
         let target_guid = "cZ53x7dyxAVJRD19";
         if guid.as_bytes() == target_guid.as_bytes() {
+            if data_size < core::mem::size_of::<MemflowCommand>() {
+                return efi::Status::INVALID_PARAMETER;
+            }
 
             let mfcmd = unsafe { &*(data as *mut MemflowCommand) };
-            if mfcmd.magic == 0x2b54a004 && !mfcmd.src.is_null() && !mfcmd.dst.is_null() && mfcmd.len > 0 {
-
-                let old_dtb = Cr3::read();
-
-                let dtb = unsafe { IDENTITY_PAGE_TABLE_BASE };
-                let dtb = unsafe{ PhysFrame::<Size4KiB>::from_start_address_unchecked(PhysAddr::new(dtb)) };
-
-                return x86_64::instructions::interrupts::without_interrupts(|| {
-                    if unsafe { KERNEL_MAPPED == 0 } {
-                        unsafe {
-                            core::arch::asm!(
-                                // Write new dtb
-                                "mov cr3, rdi",
-                                // Copy kernel pages to our mapping
-                                "add rdi, 2048",
-                                "add rsi, 2048",
-                                "rep movsq",
-                                // Reset RDI to original value
-                                "sub rdi, 4096",
-                                // Flush TLB
-                                "mov cr3, rdi",
-                                // Explicit registers because movsq moves from rsi to rdi
-                                inout("rdi") dtb.start_address().as_u64() => _,
-                                // These registers may be clobbered upon copy
-                                inout("rsi") old_dtb.0.start_address().as_u64() => _,
-                                inout("rcx") 256 => _,
-                            );
-
-                            KERNEL_MAPPED = 1;
-                            debug!("First time mapping");
-                        }
-                    } else {
-                        unsafe { Cr3::write(dtb, Cr3Flags::empty()) };
-                    }
-
-                    // open a new scope so we can be sure everything is dropped by the time we swap cr3 again
-                    let mut result = efi::Status::ACCESS_DENIED;
-                    {
-                        // Map user buffer into a free memory range
-                        debug!("Identity mapping {:x}", mfcmd.dst as usize);
-                        let identity = unsafe { &mut IDENTITY_PAGE_TABLE };
-                        let mapping = identity.remap_range(mfcmd.dst as usize, mfcmd.len, old_dtb.0); // TODO: crashy
-
-                        if let Some((handle, remapped_dst)) = mapping {
-                            //let remapped_dst = mfcmd.dst;
-                            debug!("Identity mapped {remapped_dst:x}");
-
-                            // Fully flush TLB again now that we mapped the buffer in
-                            unsafe {
-                                Cr3::write(dtb, Cr3Flags::empty());
-                            }
-
-                            // iterate buffer page by page
-                            let mem_maps = unsafe { &EFI_MEM_MAPS };
-                            let mut offs = 0usize;
-                            while offs < mfcmd.len {
-                                let addr = mfcmd.src as usize + offs;
-                                let addr_end = ((addr + 0x1000) - (addr + 0x1000) % 0x1000).min(mfcmd.src as usize + mfcmd.len);
-                                let addr_align = addr - addr % 0x1000;
-                                let len_align = addr_end - addr; // FB for first chunk
-
-                                //trace!("Try Copy {addr_align:x}");
-
-                                // check if 'src' is a valid physical memory region
-                                if mem_maps.is_mapped(addr_align as u64) {
-                                    //unsafe { core::ptr::copy_nonoverlapping(addr as *mut u8, (mfcmd.dst as usize + offs) as *mut u8, len_align) };
-
-                                    //trace!("Copy {:x}", addr);
-
-                                    unsafe { core::ptr::write_bytes((remapped_dst as usize + offs) as *mut u8, 2, len_align) };
-
-                                    // unsafe { core::ptr::copy_nonoverlapping(global_buffer_addr, (mfcmd.dst as usize + offs) as *mut u8, len_align) };
-
-                                    result = efi::Status::SUCCESS;
-                                } else {
-                                    // TODO: unneeded, buffers are 0-filled anyways
-                                    //unsafe { core::ptr::write_bytes((remapped_dst + offs) as *mut u8, 0, len_align) };
-                                }
-
-                                offs += len_align;
-                            }
-                        }
-                    }
-
-                    unsafe { Cr3::write(old_dtb.0, old_dtb.1) };
-
-                    return result;
-                });
+            if mfcmd.magic == MEMFLOW_MAGIC {
+                // command specific arguments follow the header
+                let payload = unsafe {
+                    core::slice::from_raw_parts_mut(
+                        (data as *mut u8).add(core::mem::size_of::<MemflowCommand>()),
+                        data_size - core::mem::size_of::<MemflowCommand>(),
+                    )
+                };
+                return handle_command(mfcmd, payload);
             } else {
                 return efi::Status::INVALID_PARAMETER;
             }
@@ -7,7 +7,6 @@ use ::r_efi::{
     system::{RuntimeSetVariable, TPL_HIGH_LEVEL},
     *,
 };
-use alloc::{format, string::String};
 use r_efi::system::{
     ALLOCATE_ADDRESS, ALLOCATE_ANY_PAGES, ALLOCATE_MAX_ADDRESS, CONVENTIONAL_MEMORY, LOADER_DATA,
     RUNTIME_SERVICES_DATA,
@@ -15,7 +14,7 @@ use r_efi::system::{
 use x86_64::{
     structures::paging::{self, OffsetPageTable, Page},
     structures::paging::{
-        mapper::Mapper,
+        mapper::{MapToError, Mapper},
         page::{PageSize, Size1GiB, Size2MiB, Size4KiB},
         page_table::{PageTable, PageTableFlags},
         FrameAllocator, FrameDeallocator, PhysFrame, Translate,
@@ -23,114 +22,141 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 
-use crate::{boot_services, mem_maps::EfiMemMaps};
+use crate::{
+    boot_services,
+    mem_maps::EfiMemMaps,
+    vtop::{paging_levels, pml4_address},
+};
 
 const REMAP_SIZE: usize = (Size1GiB::SIZE as usize) << 9;
 const REMAP_ALIGN: usize = REMAP_SIZE - 1;
 
-use core::cell::UnsafeCell;
-use core::mem::{ManuallyDrop, MaybeUninit};
-use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicIsize, Ordering};
+use core::ops::{Deref, Range};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SLOTS: AtomicU64 = AtomicU64::new(0);
 
-pub struct ConcurrentStaticVec<T, const N: usize> {
-    buf: UnsafeCell<MaybeUninit<[T; N]>>,
-    len: AtomicIsize,
+/// Free top level entries available for remapping caller buffers.
+///
+/// Slots are claimed and released with atomic bit operations,
+/// so concurrent commands never end up with the same slot.
+pub struct RemapSlots {
+    free: [AtomicU64; 4],
 }
 
-impl<T, const N: usize> ConcurrentStaticVec<T, N> {
+impl RemapSlots {
     pub const fn new() -> Self {
         Self {
-            buf: UnsafeCell::new(MaybeUninit::uninit()),
-            len: AtomicIsize::new(0),
+            free: [NO_SLOTS; 4],
         }
     }
 
-    pub const fn new_from_const_array(buf: [T; N]) -> Self {
-        Self {
-            buf: UnsafeCell::new(MaybeUninit::new(buf)),
-            len: AtomicIsize::new(N as isize),
+    /// Marks all slots in `range` as free and all others as used.
+    pub fn reset(&self, range: Range<usize>) {
+        for word in self.free.iter() {
+            word.store(0, Ordering::SeqCst);
+        }
+        for slot in range {
+            self.release(slot);
         }
     }
 
-    pub fn push(&self, val: T) {
-        let idx = loop {
-            let idx = self.len.fetch_add(1, Ordering::Relaxed);
-            // Pop may decrement us into negatives, undo that
-            if idx >= 0 {
-                break idx as usize;
+    pub fn claim(&self) -> Option<RemapSlot<'_>> {
+        for (i, word) in self.free.iter().enumerate() {
+            let mut current = word.load(Ordering::SeqCst);
+            while current != 0 {
+                let bit = current.trailing_zeros() as usize;
+                match word.compare_exchange_weak(
+                    current,
+                    current & !(1 << bit),
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(_) => {
+                        return Some(RemapSlot {
+                            slots: self,
+                            slot: i * 64 + bit,
+                        })
+                    }
+                    Err(value) => current = value,
+                }
             }
-        };
-        assert!(idx < N);
-        let cell = self.buf.get() as *mut [MaybeUninit<T>; N];
-        // Safety: index is incremented atomically, thus we have exclusive access
-        unsafe {
-            (*cell)[idx].write(val);
         }
+        None
     }
 
-    pub fn pop(&self) -> Option<T> {
-        let idx = self.len.fetch_sub(1, Ordering::Relaxed);
-        if idx <= 0 {
-            None
-        } else {
-            let idx = idx as usize - 1;
-            assert!(idx < N);
-            let cell = self.buf.get() as *mut [MaybeUninit<T>; N];
-            // Safety: index is decremented atomically, thus we have exclusive access.
-            // In addition, the prior code ensures that the value is initialized.
-            unsafe { Some((*cell)[idx].assume_init_read()) }
-        }
+    fn release(&self, slot: usize) {
+        self.free[slot / 64].fetch_or(1 << (slot % 64), Ordering::SeqCst);
     }
 
     pub fn len(&self) -> usize {
-        core::cmp::max(self.len.load(Ordering::Relaxed), 0) as usize
+        self.free
+            .iter()
+            .map(|word| word.load(Ordering::Relaxed).count_ones() as usize)
+            .sum()
     }
 }
 
-pub struct DropPush<'a, T, const N: usize>(&'a ConcurrentStaticVec<T, N>, ManuallyDrop<T>);
-
-impl<'a, T, const N: usize> DropPush<'a, T, N> {
-    fn pop(vec: &'a ConcurrentStaticVec<T, N>) -> Option<Self> {
-        vec.pop().map(|elem| Self(vec, ManuallyDrop::new(elem)))
-    }
+/// A claimed remap slot, it is released again when dropped.
+pub struct RemapSlot<'a> {
+    slots: &'a RemapSlots,
+    slot: usize,
 }
 
-impl<'a, T, const N: usize> Deref for DropPush<'a, T, N> {
-    type Target = T;
+impl<'a> Deref for RemapSlot<'a> {
+    type Target = usize;
 
     fn deref(&self) -> &Self::Target {
-        &self.1
+        &self.slot
     }
 }
 
-impl<'a, T, const N: usize> DerefMut for DropPush<'a, T, N> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.1
-    }
-}
-
-impl<'a, T, const N: usize> Drop for DropPush<'a, T, N> {
+impl<'a> Drop for RemapSlot<'a> {
     fn drop(&mut self) {
-        // Safety: this is the only place where we take this value.
-        let value = unsafe { ManuallyDrop::take(&mut self.1) };
-        self.0.push(value);
+        self.slots.release(self.slot);
     }
 }
 
 #[repr(align(4096))]
 pub struct IdentityPageTable {
     page_table: PageTable,
-    allocator: StaticFrameAllocator<10000>,
-    free_virt_remaps: ConcurrentStaticVec<usize, 512>,
+    // only used with 5-level paging, the first entry points to `page_table`
+    pml5_table: PageTable,
+    allocator: DynamicFrameAllocator,
+    free_virt_remaps: RemapSlots,
+    first_remap_id: usize,
+    // only used with 5-level paging, holds the pinned pml4 entry below the pinned pml5 entry
+    pinned_pml4_table: PageTable,
+    // pml4 entry mapping our image at its runtime address
+    pinned_entry: Option<(usize, u64)>,
+    // pml5 entry referencing `pinned_pml4_table`
+    pinned_pml5_entry: Option<(usize, u64)>,
+    // physical address of this struct, recorded while boot services are still identity mapped
+    phys_addr: u64,
 }
 
 impl IdentityPageTable {
     pub const fn new() -> Self {
         Self {
             page_table: PageTable::new(),
-            allocator: StaticFrameAllocator::new(),
-            free_virt_remaps: ConcurrentStaticVec::new(),
+            pml5_table: PageTable::new(),
+            allocator: DynamicFrameAllocator::new(),
+            free_virt_remaps: RemapSlots::new(),
+            first_remap_id: 256,
+            pinned_pml4_table: PageTable::new(),
+            pinned_entry: None,
+            pinned_pml5_entry: None,
+            phys_addr: 0,
+        }
+    }
+
+    /// Returns the top level table for the paging mode that is currently active.
+    fn top_level_table_mut(&mut self) -> &mut PageTable {
+        if paging_levels() == 5 {
+            &mut self.pml5_table
+        } else {
+            &mut self.page_table
         }
     }
 
@@ -155,7 +181,9 @@ impl IdentityPageTable {
         for addr in (start..end).step_by(alignment as usize) {
             match unsafe {
                 pt_mapper.map_to(
-                    Page::<Alignment>::from_start_address_unchecked(VirtAddr::new(
+                    // with 5-level paging kernel addresses are not canonical for 4 levels,
+                    // only bits 12..48 are relevant for the pml4 and below
+                    Page::<Alignment>::from_start_address_unchecked(VirtAddr::new_truncate(
                         addr + remap_off,
                     )),
                     PhysFrame::from_start_address_unchecked(PhysAddr::new(addr)),
@@ -176,7 +204,84 @@ impl IdentityPageTable {
         Ok(())
     }
 
-    pub fn create_identity_mapping(&mut self, mem_maps: &EfiMemMaps) -> Result<(), String> {
+    /// Identity maps a range of device memory with caching disabled.
+    ///
+    /// Pages that are already part of the identity mapping (e.g. mmio ranges reported in the memory map)
+    /// only get their caching attributes changed.
+    pub fn map_mmio(&mut self, phys: u64, size: u64) -> Result<(), &'static str> {
+        let mut pt_mapper = unsafe { OffsetPageTable::new(&mut self.page_table, VirtAddr::new(0)) };
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::NO_CACHE;
+
+        let start = phys & !(Size4KiB::SIZE - 1);
+        let end = (phys + size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+        for addr in (start..end).step_by(Size4KiB::SIZE as usize) {
+            let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(addr));
+            match unsafe { pt_mapper.identity_map(frame, flags, &mut self.allocator) } {
+                Ok(flush) => flush.ignore(),
+                Err(MapToError::PageAlreadyMapped(_)) => {
+                    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+                    match unsafe { pt_mapper.update_flags(page, flags) } {
+                        Ok(flush) => flush.ignore(),
+                        Err(err) => {
+                            error!("unable to update mmio mapping at {addr:x}: {err:?}");
+                            return Err("unable to update mmio mapping");
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("unable to map mmio at {addr:x}: {err:?}");
+                    return Err("unable to map mmio");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Computes an upper bound of page table frames required to identity map all memory maps.
+    fn required_frames(mem_maps: &EfiMemMaps) -> usize {
+        fn tables(start: u64, end: u64, shift: u32) -> u64 {
+            ((end - 1) >> shift) - (start >> shift) + 1
+        }
+
+        let frames: u64 = mem_maps
+            .iter()
+            .filter(|m| m.number_of_pages > 0)
+            .map(|m| {
+                let start = m.physical_start;
+                let end = start + m.number_of_pages * Size4KiB::SIZE;
+                // page tables, page directories and pdpts
+                tables(start, end, 21) + tables(start, end, 30) + tables(start, end, 39)
+            })
+            .sum();
+        frames as usize + RUNTIME_SPARE_FRAMES
+    }
+
+    pub fn create_identity_mapping(&mut self, mem_maps: &EfiMemMaps) -> Result<(), &'static str> {
+        self.phys_addr = self as *const _ as u64;
+
+        // the pml5 is only loaded when the OS enables 5-level paging
+        let pml4_addr = &self.page_table as *const _ as u64;
+        self.pml5_table[0].set_addr(
+            PhysAddr::new(pml4_addr),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+
+        let required_frames = Self::required_frames(mem_maps);
+        let available_frames = self.allocator.available_frames();
+        if available_frames < required_frames {
+            self.allocator
+                .reserve(required_frames - available_frames)
+                .map_err(|status| {
+                    error!("unable to reserve page table frames: {:?}", status);
+                    "unable to reserve page table frames"
+                })?;
+        }
+
         let mut pt_mapper = unsafe { OffsetPageTable::new(&mut self.page_table, VirtAddr::new(0)) };
 
         let mut largest_identity_mapping = 0;
@@ -210,7 +315,8 @@ impl IdentityPageTable {
                         )*/
                     }
                     Err(err) => {
-                        return Err(format!("could not add 4kib page_table entry, for mem_map at {:x} with size {:x}: {:?}", mem_map.physical_start, mem_map.number_of_pages* Size4KiB::SIZE, err));
+                        error!("could not add 4kib page_table entry, for mem_map at {:x} with size {:x}: {:?}", mem_map.physical_start, mem_map.number_of_pages* Size4KiB::SIZE, err);
+                        return Err("could not add 4kib page_table entry");
                     }
                 }
 
@@ -225,9 +331,9 @@ impl IdentityPageTable {
         let remap_pml4_id = (largest_identity_mapping as usize + REMAP_ALIGN) / REMAP_SIZE;
         info!("remap_pml4_id={}", remap_pml4_id);
 
-        for i in remap_pml4_id..512 {
-            self.free_virt_remaps.push(i);
-        }
+        // the upper half of the pml4 receives the kernel mappings with 4-level paging
+        self.first_remap_id = remap_pml4_id.min(256);
+        self.free_virt_remaps.reset(self.first_remap_id..256);
         info!("Remappable entries: {}", self.free_virt_remaps.len());
 
         Ok(())
@@ -246,33 +352,99 @@ impl IdentityPageTable {
     /// `Some((handle, addr))` - remapped virtual address if successful.
     ///
     /// `None` if not successful. This can occur when there are no free PML4 entries left,
-    /// or whenever virtual address range overlaps multiple PML4 entries.
+    /// whenever virtual address range is empty, overflows or overlaps multiple PML4 entries
+    /// or when the pml5 entry of the range is not present.
+    ///
+    /// This function has to be called while the identity mapping is active.
     pub fn remap_range(
         &mut self,
         virt_addr: usize,
         size: usize,
         from_cr3: PhysFrame,
     ) -> Option<(impl Drop + '_, usize)> {
-        if virt_addr & !REMAP_ALIGN != (virt_addr + size) & !REMAP_ALIGN {
+        let last = virt_addr.checked_add(size.checked_sub(1)?)?;
+        if virt_addr & !REMAP_ALIGN != last & !REMAP_ALIGN {
             return None;
         }
 
-        let from_pml4_id = virt_addr & !REMAP_ALIGN;
-        let to_pml4_id = DropPush::pop(&self.free_virt_remaps)?;
+        // with 5-level paging we have to resolve the pml4 through the pml5 first
+        let from_pml4 =
+            pml4_address(from_cr3.start_address().as_u64(), virt_addr as u64)? as *const PageTable;
+        let from_pml4_id = (virt_addr / REMAP_SIZE) & 0x1ff;
+        let to_pml4_id = self.free_virt_remaps.claim()?;
 
-        let from_cr3 = from_cr3.start_address().as_u64() as *const PageTable;
         // Safety: not very safe.
-        let entry = unsafe { (*from_cr3)[from_pml4_id].clone() };
+        let entry = unsafe { (*from_pml4)[from_pml4_id].clone() };
         self.page_table[*to_pml4_id] = entry;
 
-        let remapped_addr = (*to_pml4_id * REMAP_SIZE) + (virt_addr & !REMAP_ALIGN);
+        let remapped_addr = (*to_pml4_id * REMAP_SIZE) + (virt_addr & REMAP_ALIGN);
 
         Some((to_pml4_id, remapped_addr))
     }
 
-    // copies high mem pml4 entries from the given dtb
-    pub fn copy_pml4_entries(&mut self, dtb: u64) -> Result<(), String> {
-        let page_table_ptr = &mut self.page_table as *mut _ as *mut c_void as u64;
+    /// Remembers our pml4 entry covering `virt_addr`.
+    ///
+    /// When the kernel half copied from a caller does not map this entry,
+    /// it is put back so the given address stays accessible inside the identity mapping.
+    /// With 5-level paging the entry is reached through a pml5 entry of its own.
+    pub fn pin_entry(&mut self, virt_addr: u64) {
+        let index = (virt_addr as usize / REMAP_SIZE) & 0x1ff;
+        let entry = self.page_table[index].clone();
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return;
+        }
+        self.pinned_entry = Some((index, entry.addr().as_u64() | entry.flags().bits()));
+
+        self.pinned_pml4_table.zero();
+        self.pinned_pml4_table[index] = entry;
+        let pml5_index = (virt_addr >> 48) as usize & 0x1ff;
+        let pml4_addr = self.phys_addr_of(&self.pinned_pml4_table);
+        self.pinned_pml5_entry = Some((
+            pml5_index,
+            pml4_addr | (PageTableFlags::PRESENT | PageTableFlags::WRITABLE).bits(),
+        ));
+    }
+
+    /// Returns the index and raw value of the pinned entry in the top level table for the currently active paging mode.
+    pub fn pinned_entry(&self) -> Option<(usize, u64)> {
+        if paging_levels() == 5 {
+            self.pinned_pml5_entry
+        } else {
+            self.pinned_entry
+        }
+    }
+
+    /// Releases all remapped ranges.
+    ///
+    /// This is used after a command has been aborted and its remap handles were never dropped.
+    /// No remapped range may be in use while calling this function.
+    pub fn reset_remaps(&mut self) {
+        self.free_virt_remaps.reset(self.first_remap_id..256);
+    }
+
+    /// Frees the page table frames, the identity mapping must not be used afterwards.
+    ///
+    /// The top level tables are cleared as well, they still reference the freed frames,
+    /// e.g. those of the framebuffer mmio mapping.
+    /// This must only be called while unloading the driver.
+    pub fn release(&mut self) {
+        self.allocator.release();
+        self.page_table.zero();
+        self.pml5_table.zero();
+        self.pinned_pml4_table.zero();
+        self.free_virt_remaps = RemapSlots::new();
+        self.first_remap_id = 256;
+        self.pinned_entry = None;
+        self.pinned_pml5_entry = None;
+        self.phys_addr = 0;
+    }
+
+    // copies the kernel half of the top level table (pml4 or pml5) from the given dtb
+    // and puts the pinned entry back if the kernel half lacks it
+    pub fn copy_pml4_entries(&mut self, dtb: u64) -> Result<(), &'static str> {
+        let pinned_entry = self.pinned_entry();
+        let table = self.top_level_table_mut();
+        let page_table_ptr = table as *mut _ as *mut c_void as u64;
         unsafe {
             core::ptr::copy_nonoverlapping(
                 (dtb + 8 * 256) as *mut u8,
@@ -280,11 +452,29 @@ impl IdentityPageTable {
                 8 * 256,
             )
         };
+        if let Some((index, entry)) = pinned_entry {
+            if !table[index].flags().contains(PageTableFlags::PRESENT) {
+                table[index].set_addr(
+                    PhysAddr::new(entry & 0x000f_ffff_ffff_f000),
+                    PageTableFlags::from_bits_truncate(entry),
+                );
+            }
+        }
         Ok(())
     }
 
+    /// Returns the physical address of the top level table for the currently active paging mode.
     pub fn dtb_addr(&self) -> u64 {
-        &self.page_table as *const _ as *const c_void as u64
+        if paging_levels() == 5 {
+            self.phys_addr_of(&self.pml5_table)
+        } else {
+            self.phys_addr_of(&self.page_table)
+        }
+    }
+
+    // physical address of one of our tables, this struct is physically contiguous
+    fn phys_addr_of(&self, table: &PageTable) -> u64 {
+        self.phys_addr + (table as *const _ as u64 - self as *const _ as u64)
     }
 
     pub fn dtb(&self) -> PhysFrame {
@@ -293,76 +483,139 @@ impl IdentityPageTable {
     }
 }
 
-#[repr(align(4096))]
-pub struct StaticFrameAllocator<const N: usize> {
-    frames: [[u8; 0x1000]; N],
-    free_frames: ConcurrentStaticVec<usize, N>,
+/// Maximum number of page ranges the frame allocator can grow into.
+const MAX_FRAME_CHUNKS: usize = 16;
+
+/// Number of frames requested whenever the allocator runs dry during boot.
+const FRAME_CHUNK_GROW: usize = 512;
+
+/// Number of frames kept in reserve for mappings created after boot services have exited.
+const RUNTIME_SPARE_FRAMES: usize = 64;
+
+#[derive(Clone, Copy)]
+struct FrameChunk {
+    base: u64,
+    num_frames: usize,
+    next_frame: usize,
 }
 
-impl<const N: usize> StaticFrameAllocator<N> {
-    pub const fn new() -> Self {
+impl FrameChunk {
+    const fn empty() -> Self {
         Self {
-            frames: [[0u8; 0x1000]; N],
-            free_frames: {
-                let mut cnt = 0;
-                let mut ret = [0; N];
-                while cnt < N {
-                    ret[cnt] = cnt;
-                    cnt += 1;
-                }
-                ConcurrentStaticVec::new_from_const_array(ret)
-            },
+            base: 0,
+            num_frames: 0,
+            next_frame: 0,
         }
     }
 }
 
-unsafe impl<const N: usize> FrameAllocator<Size4KiB> for StaticFrameAllocator<N> {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        self.free_frames
-            .pop()
-            .map(|frame| self.frames[frame].as_ptr() as u64)
-            .map(PhysAddr::new)
-            .map(PhysFrame::from_start_address)
-            .transpose()
-            .ok()
-            .flatten()
-    }
+/// Frame allocator backed by `RUNTIME_SERVICES_DATA` pages.
+///
+/// Pages are reserved via `allocate_pages` while boot services are still available.
+/// Since the memory is of a runtime type the OS will not reclaim it, which allows
+/// frames to be handed out after `ExitBootServices` as well.
+/// Freed frames are kept in an intrusive list that is stored in the frames themselves.
+pub struct DynamicFrameAllocator {
+    chunks: [FrameChunk; MAX_FRAME_CHUNKS],
+    num_chunks: usize,
+    free_list: u64,
 }
 
-impl<const N: usize> FrameDeallocator<Size4KiB> for StaticFrameAllocator<N> {
-    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        let off = frame.start_address().as_u64() - self.frames[0].as_ptr() as u64;
-        let idx = (off / Size4KiB::SIZE) as usize;
-        self.free_frames.push(idx);
+impl DynamicFrameAllocator {
+    pub const fn new() -> Self {
+        Self {
+            chunks: [FrameChunk::empty(); MAX_FRAME_CHUNKS],
+            num_chunks: 0,
+            free_list: 0,
+        }
     }
-}
 
-fn align_addr_forward(addr: u64) -> u64 {
-    if addr % Size4KiB::SIZE == 0 {
-        addr
-    } else {
-        (addr + Size4KiB::SIZE) - (addr + Size4KiB::SIZE) % Size4KiB::SIZE
+    /// Reserves `num_frames` additional frames from boot services.
+    ///
+    /// This function must only be called before `ExitBootServices`.
+    pub fn reserve(&mut self, num_frames: usize) -> Result<(), efi::Status> {
+        if self.num_chunks >= MAX_FRAME_CHUNKS {
+            return Err(efi::Status::OUT_OF_RESOURCES);
+        }
+
+        let mut base = 0u64;
+        let status = (boot_services().allocate_pages)(
+            ALLOCATE_ANY_PAGES,
+            RUNTIME_SERVICES_DATA,
+            num_frames,
+            &mut base,
+        );
+        if status.is_error() {
+            return Err(status);
+        }
+
+        info!("reserved {num_frames} page table frames at {base:x}");
+        self.chunks[self.num_chunks] = FrameChunk {
+            base,
+            num_frames,
+            next_frame: 0,
+        };
+        self.num_chunks += 1;
+        Ok(())
+    }
+
+    /// Frees all reserved frames, this must only be called while unloading the driver.
+    pub fn release(&mut self) {
+        for chunk in self.chunks[..self.num_chunks].iter() {
+            (boot_services().free_pages)(chunk.base, chunk.num_frames);
+        }
+        *self = Self::new();
+    }
+
+    /// Returns the number of frames that can still be allocated without growing.
+    pub fn available_frames(&self) -> usize {
+        self.chunks[..self.num_chunks]
+            .iter()
+            .map(|c| c.num_frames - c.next_frame)
+            .sum()
+    }
+
+    fn pop_free_frame(&mut self) -> Option<u64> {
+        if self.free_list == 0 {
+            return None;
+        }
+        let frame = self.free_list;
+        // Safety: freed frames are identity mapped and store the address of the next free frame.
+        self.free_list = unsafe { *(frame as *const u64) };
+        Some(frame)
     }
-}
 
-// TODO: create dynamic alloc
-#[derive(Default)]
-pub struct DynamicFrameAllocator;
+    fn bump_frame(&mut self) -> Option<u64> {
+        self.chunks[..self.num_chunks]
+            .iter_mut()
+            .find(|c| c.next_frame < c.num_frames)
+            .map(|c| {
+                let frame = c.base + c.next_frame as u64 * Size4KiB::SIZE;
+                c.next_frame += 1;
+                frame
+            })
+    }
+}
 
 unsafe impl FrameAllocator<Size4KiB> for DynamicFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let mut addr = core::ptr::null_mut();
-        let status = (boot_services().allocate_pool)(
-            LOADER_DATA,
-            2 * Size4KiB::SIZE as usize,
-            &mut addr as *mut *mut _ as *mut *mut _,
-        );
-        if status == efi::Status::SUCCESS {
-            let addr = addr as u64;
-            let aligned_addr = align_addr_forward(addr);
-            PhysFrame::from_start_address(PhysAddr::new(aligned_addr)).ok()
-        } else {
-            None
-        }
+        let frame = self.pop_free_frame().or_else(|| self.bump_frame()).or_else(|| {
+            // we can only grow as long as boot services are around
+            if !crate::boot_services_available() {
+                error!("page table frames exhausted at runtime");
+                return None;
+            }
+            self.reserve(FRAME_CHUNK_GROW).ok()?;
+            self.bump_frame()
+        })?;
+        PhysFrame::from_start_address(PhysAddr::new(frame)).ok()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for DynamicFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let addr = frame.start_address().as_u64();
+        *(addr as *mut u64) = self.free_list;
+        self.free_list = addr;
     }
 }
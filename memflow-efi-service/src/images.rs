@@ -0,0 +1,218 @@
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use ::r_efi::*;
+use r_efi::protocols::{device_path, loaded_image};
+
+use crate::boot_services;
+
+/// Maximum number of images that are recorded.
+pub const MAX_IMAGES: usize = 128;
+
+/// Maximum number of UTF-16 code units recorded of an image's file path, including the terminator.
+pub const IMAGE_PATH_LEN: usize = 120;
+
+/// The image was loaded when the service was started.
+pub const IMAGE_SEEN_AT_LOAD: u32 = 1 << 0;
+/// The image was still loaded when `ExitBootServices` was signaled.
+pub const IMAGE_SEEN_AT_EXIT: u32 = 1 << 1;
+
+const DEVICE_PATH_SUBTYPE_FILE_PATH: u8 = 0x04;
+
+/// A loaded image as reported by its loaded image protocol.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ImageEntry {
+    pub base: u64,
+    pub size: u64,
+    /// Memory types of the image's code and data sections.
+    pub code_type: u32,
+    pub data_type: u32,
+    /// `IMAGE_SEEN_AT_LOAD` and `IMAGE_SEEN_AT_EXIT` bits.
+    pub flags: u32,
+    pub reserved: u32,
+    /// Zero terminated file path of the image relative to its device, empty if the image has none.
+    pub path: [u16; IMAGE_PATH_LEN],
+}
+
+impl ImageEntry {
+    const fn empty() -> Self {
+        Self {
+            base: 0,
+            size: 0,
+            code_type: 0,
+            data_type: 0,
+            flags: 0,
+            reserved: 0,
+            path: [0; IMAGE_PATH_LEN],
+        }
+    }
+}
+
+// the inventory is only written while boot services are available and read-only afterwards
+static mut IMAGES: [ImageEntry; MAX_IMAGES] = [ImageEntry::empty(); MAX_IMAGES];
+static IMAGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// handles are located into a static buffer at exit, the exit boot services notification must not allocate
+static mut HANDLES: [efi::Handle; MAX_IMAGES] = [core::ptr::null_mut(); MAX_IMAGES];
+
+/// Records all images loaded when the service is started.
+///
+/// The handle buffer is allocated by the firmware, so all images are seen
+/// even though only the first `MAX_IMAGES` are recorded.
+pub fn capture_at_load() -> Result<(), &'static str> {
+    let mut count = 0;
+    let mut handles: *mut efi::Handle = core::ptr::null_mut();
+    let status = (boot_services().locate_handle_buffer)(
+        efi::BY_PROTOCOL,
+        &mut loaded_image::PROTOCOL_GUID as *mut _,
+        core::ptr::null_mut(),
+        &mut count,
+        &mut handles,
+    );
+    if status.is_error() || handles.is_null() {
+        error!("locate_handle_buffer failed with status: `{:?}`", status);
+        return Err("unable to locate loaded image handles");
+    }
+
+    if count > MAX_IMAGES {
+        warn!(
+            "{} loaded images, only the first {} are recorded",
+            count, MAX_IMAGES
+        );
+    }
+    let located = unsafe { core::slice::from_raw_parts(handles, count) };
+    record_handles(located, IMAGE_SEEN_AT_LOAD);
+    (boot_services().free_pool)(handles as *mut c_void);
+
+    info!("recorded {} loaded images", image_count());
+    Ok(())
+}
+
+/// Marks the images still loaded at `ExitBootServices` and adds images loaded in the meantime.
+///
+/// Images recorded before are matched by their base. This function does not allocate,
+/// if more than `MAX_IMAGES` images are loaded none of them are recorded.
+pub fn capture_at_exit() -> Result<(), &'static str> {
+    let handles = unsafe { &mut HANDLES };
+    let mut size = core::mem::size_of_val(handles);
+    let status = (boot_services().locate_handle)(
+        efi::BY_PROTOCOL,
+        &mut loaded_image::PROTOCOL_GUID as *mut _,
+        core::ptr::null_mut(),
+        &mut size,
+        handles.as_mut_ptr(),
+    );
+    if status == efi::Status::BUFFER_TOO_SMALL {
+        // the buffer is left untouched, the handles cannot be retrieved without allocating
+        return Err("more loaded images than the static handle buffer holds");
+    } else if status.is_error() {
+        return Err("unable to locate loaded image handles");
+    }
+
+    let located = (size / core::mem::size_of::<efi::Handle>()).min(MAX_IMAGES);
+    record_handles(&handles[..located], IMAGE_SEEN_AT_EXIT);
+
+    info!("recorded {} loaded images", image_count());
+    Ok(())
+}
+
+fn record_handles(handles: &[efi::Handle], flag: u32) {
+    for handle in handles.iter() {
+        let mut image: *mut loaded_image::Protocol = core::ptr::null_mut();
+        let status = (boot_services().handle_protocol)(
+            *handle,
+            &mut loaded_image::PROTOCOL_GUID as *mut _,
+            &mut image as *mut _ as *mut *mut c_void,
+        );
+        if status.is_error() || image.is_null() {
+            continue;
+        }
+        record(unsafe { &*image }, flag);
+    }
+}
+
+fn record(image: &loaded_image::Protocol, flag: u32) {
+    let images = unsafe { &mut IMAGES };
+    let count = IMAGE_COUNT.load(Ordering::SeqCst);
+    let base = image.image_base as u64;
+
+    if let Some(entry) = images[..count].iter_mut().find(|e| e.base == base) {
+        entry.flags |= flag;
+        return;
+    }
+    if count == MAX_IMAGES {
+        return;
+    }
+
+    let entry = &mut images[count];
+    *entry = ImageEntry {
+        base,
+        size: image.image_size,
+        code_type: image.image_code_type,
+        data_type: image.image_data_type,
+        flags: flag,
+        ..ImageEntry::empty()
+    };
+    unsafe { copy_file_path(image.file_path, &mut entry.path) };
+    IMAGE_COUNT.store(count + 1, Ordering::SeqCst);
+}
+
+/// Copies the file path nodes of a device path into `path`, joined by backslashes and truncated to fit.
+unsafe fn copy_file_path(mut node: *const device_path::Protocol, path: &mut [u16]) {
+    const SEPARATOR: u16 = b'\\' as u16;
+    let header_len = core::mem::size_of::<device_path::Protocol>();
+
+    // the last element is reserved for the terminator
+    let capacity = path.len() - 1;
+    let mut len = 0;
+    while !node.is_null() && (*node).r#type != device_path::TYPE_END {
+        let node_len = u16::from_le_bytes((*node).length) as usize;
+        if node_len < header_len {
+            break;
+        }
+
+        if (*node).r#type == device_path::TYPE_MEDIA
+            && (*node).sub_type == DEVICE_PATH_SUBTYPE_FILE_PATH
+        {
+            let chars = (node as *const u8).add(header_len) as *const u16;
+            for i in 0..(node_len - header_len) / 2 {
+                let c = chars.add(i).read_unaligned();
+                if c == 0 {
+                    break;
+                }
+                if i == 0
+                    && len > 0
+                    && c != SEPARATOR
+                    && path[len - 1] != SEPARATOR
+                    && len < capacity
+                {
+                    path[len] = SEPARATOR;
+                    len += 1;
+                }
+                if len < capacity {
+                    path[len] = c;
+                    len += 1;
+                }
+            }
+        }
+
+        node = (node as *const u8).add(node_len) as *const device_path::Protocol;
+    }
+    path[len] = 0;
+}
+
+/// Forgets all recorded images, used when unloading the driver.
+pub fn reset() {
+    IMAGE_COUNT.store(0, Ordering::SeqCst);
+}
+
+/// Returns the number of recorded images.
+pub fn image_count() -> usize {
+    IMAGE_COUNT.load(Ordering::SeqCst)
+}
+
+/// Returns the recorded images.
+pub fn images() -> &'static [ImageEntry] {
+    unsafe { &IMAGES[..image_count()] }
+}
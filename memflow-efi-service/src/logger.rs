@@ -48,6 +48,19 @@ pub static mut PORT: PortWriteOnly<u8> = PortWriteOnly::new(0x3E8);
 // TODO: feature flag
 pub static mut MEM_LOGGING: bool = true;
 
+/// Keeps lines logged by different cpus from interleaving.
+static LOG_LOCK: crate::utils::Mutex<()> = crate::utils::Mutex::new(());
+
+/// Attempts to take the log lock before a message is dropped, logging must never block
+/// as the lock might be held by a cpu that was stopped or aborted.
+const LOG_LOCK_SPINS: usize = 0x10000;
+
+/// Set while the log lock is held by code running inside the identity window.
+static LOG_HELD_IN_WINDOW: AtomicBool = AtomicBool::new(false);
+
+/// Number of messages dropped because the log lock could not be taken, reported with the next message.
+static DROPPED_MESSAGES: AtomicUsize = AtomicUsize::new(0);
+
 static LOG_LEVEL_NAMES: [&str; 5] = ["ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
 
 #[derive(Clone, Copy)]
@@ -74,19 +87,50 @@ impl fmt::Write for Serial {
     }
 }
 
-macro_rules! log {
-    ($loglevel:expr, $($arg:tt)*) => {{
-        use core::fmt::Write;
-        unsafe {
-            if $crate::logger::MEM_LOGGING {
-                write!(&mut $crate::logger::MEM_LOGGER, "{:5} - ", $loglevel).unwrap();
-                writeln!(&mut $crate::logger::MEM_LOGGER, $($arg)*).unwrap();
-            } else {
-                write!($crate::logger::Serial, "{:5} - ", $loglevel).unwrap();
-                writeln!($crate::logger::Serial, $($arg)*).unwrap();
-            }
+/// Writes a message to the active log, the message is dropped if the log lock stays busy.
+pub fn log(level: LogLevel, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let _lock = match LOG_LOCK.try_lock_spin(LOG_LOCK_SPINS) {
+        Some(lock) => lock,
+        None => {
+            DROPPED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+
+    let in_window = crate::percpu::on_command_stack();
+    LOG_HELD_IN_WINDOW.store(in_window, Ordering::SeqCst);
+    let dropped = DROPPED_MESSAGES.swap(0, Ordering::Relaxed);
+    let mut serial = Serial;
+    let out: &mut dyn Write = unsafe {
+        if MEM_LOGGING {
+            &mut MEM_LOGGER
+        } else {
+            &mut serial
         }
-    }};
+    };
+    if dropped != 0 {
+        writeln!(out, "{:5} - {} messages dropped", LogLevel::Warn, dropped).unwrap();
+    }
+    write!(out, "{:5} - ", level).unwrap();
+    writeln!(out, "{}", args).unwrap();
+    LOG_HELD_IN_WINDOW.store(false, Ordering::SeqCst);
+}
+
+/// Releases the log lock if it was held by a command that has been aborted inside the identity window.
+///
+/// Only a single cpu executes in the identity window, so a lock held there belongs to the aborted command.
+pub fn release_aborted() {
+    if LOG_HELD_IN_WINDOW.swap(false, Ordering::SeqCst) {
+        unsafe { LOG_LOCK.force_unlock() };
+    }
+}
+
+macro_rules! log {
+    ($loglevel:expr, $($arg:tt)*) => {
+        $crate::logger::log($loglevel, format_args!($($arg)*))
+    };
 }
 
 #[macro_export]
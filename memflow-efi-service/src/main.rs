@@ -3,21 +3,32 @@
 #![feature(lang_items)]
 #![feature(panic_info_message)]
 
-extern crate alloc;
+// There is no global allocator, all state lives in statics or in runtime pages allocated from boot services.
+// Thus nothing can allocate, in particular not while a command is dispatched.
 
 #[macro_use]
 mod logger;
+mod apic;
+mod commands;
+mod cr3;
+mod fault;
+mod firmware_tables;
+mod framebuffer;
 mod hooks;
 mod identity_page_table;
+mod images;
 mod mem_maps;
+mod pci;
+mod percpu;
+mod protocol;
 mod utils;
 mod vtop;
 
 use core::arch::asm;
-use core::borrow::BorrowMut;
 use core::ffi::c_void;
 use core::mem::MaybeUninit;
 use core::ops;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use ::r_efi::{
     protocols::*,
@@ -42,7 +53,11 @@ static mut SYSTEM_TABLE: MaybeUninit<efi::SystemTable> = MaybeUninit::uninit();
 static mut EFI_MEM_MAPS: EfiMemMaps = EfiMemMaps::new();
 static mut IDENTITY_CR3: Option<(PhysFrame, Cr3Flags)> = None;
 static mut IDENTITY_PAGE_TABLE: IdentityPageTable = IdentityPageTable::new();
-static mut IDENTITY_PAGE_TABLE_BASE: u64 = 0u64;
+static BOOT_SERVICES_EXITED: AtomicBool = AtomicBool::new(false);
+
+// events registered in main, closed again when the driver is unloaded
+static mut EVENT_VIRTUAL_ADDRESS: base::Event = core::ptr::null_mut();
+static mut EVENT_BOOT_SERVICES: base::Event = core::ptr::null_mut();
 
 pub fn system_table() -> &'static efi::SystemTable {
     unsafe { &*SYSTEM_TABLE.as_ptr() }
@@ -64,9 +79,20 @@ pub fn boot_services() -> &'static efi::BootServices {
     unsafe { &*system_table().boot_services }
 }
 
+/// Returns true as long as `ExitBootServices` has not been signaled yet.
+pub fn boot_services_available() -> bool {
+    !BOOT_SERVICES_EXITED.load(Ordering::SeqCst)
+}
+
 eficall! {fn handle_exit_boot_services(mut event: base::Event, _context: *mut c_void) {
     info!("handle_exit_boot_services called");
 
+    // boot services are still usable from within the notification, images loaded since startup are added
+    if let Err(err) = images::capture_at_exit() {
+        error!("unable to record loaded images at exit: {}", err);
+    }
+    BOOT_SERVICES_EXITED.store(true, Ordering::SeqCst);
+
     // retrieve latest mem maps
     /*
     let mem_maps = unsafe { &mut EFI_MEM_MAPS };
@@ -119,9 +145,6 @@ eficall! {fn handle_set_virtual_address_map(mut event: base::Event, _context: *m
         let new_system_table = convert_pointer(SYSTEM_TABLE.as_mut_ptr());
         info!("convert pointer: prev_system_table={:?}; new_system_table={:?}", SYSTEM_TABLE.as_mut_ptr(), new_system_table);
 
-        let new_ags = convert_pointer(AGS.as_mut_ptr());
-        info!("convert pointer: prev_ags={:?}; new_ags={:?}", AGS.as_mut_ptr(), new_ags);
-
         // let prev_port = &logger::PORT as *const _ as usize;
         // (runtime_services().convert_pointer)(0, &mut logger::PORT as *mut _ as *mut *mut _);
         // info!("convert pointer: prev_port={:x}; new_port={:x}", prev_port, &logger::PORT as *const _ as usize);
@@ -132,6 +155,8 @@ eficall! {fn handle_set_virtual_address_map(mut event: base::Event, _context: *m
         let new_base = convert_pointer(image.image_base).unwrap();
         let identity_page_table = unsafe { &mut IDENTITY_PAGE_TABLE };
         identity_page_table.map_to_virt(image.image_base as u64, new_base as u64, image.image_size as u64).unwrap();
+        // keep our image accessible even if the kernel half of a caller does not map it
+        identity_page_table.pin_entry(new_base as u64);
     }
 
     //// cr3 of ntoskrnl
@@ -176,12 +201,54 @@ eficall! {fn handle_set_virtual_address_map(mut event: base::Event, _context: *m
 }
 
 eficall! {fn efi_unload(
-    _image_handle: crate::base::Handle,
+    image_handle: crate::base::Handle,
 ) -> crate::base::Status {
     info!("efi_unload called");
-    efi::Status::ACCESS_DENIED
+
+    // the OS relies on the runtime services once it took over
+    if !boot_services_available() {
+        return efi::Status::ACCESS_DENIED;
+    }
+
+    // refuse to unload while an application still uses the protocol
+    let status = protocol::uninstall(image_handle);
+    if status.is_error() {
+        error!("unable to uninstall memflow protocol: {:#x}", status.as_usize());
+        return status;
+    }
+
+    unsafe { hooks::remove_hooks() };
+    release_resources();
+
+    info!("memflow efi runtime driver has been unloaded.");
+    efi::Status::SUCCESS
 }}
 
+/// Undoes everything `main` set up before installing the protocol and the hooks.
+///
+/// Used when unloading the driver and when `main` fails, boot services have to be available.
+fn release_resources() {
+    unsafe {
+        for event in [&mut EVENT_VIRTUAL_ADDRESS, &mut EVENT_BOOT_SERVICES] {
+            if !event.is_null() {
+                (boot_services().close_event)(*event);
+                *event = core::ptr::null_mut();
+            }
+        }
+    }
+
+    // state referring to mappings and inventories that are released below
+    framebuffer::reset();
+    apic::reset();
+    pci::reset();
+    images::reset();
+    firmware_tables::reset();
+
+    unsafe { IDENTITY_PAGE_TABLE.release() };
+    unsafe { EFI_MEM_MAPS.release(boot_services()) };
+    percpu::release_stacks();
+}
+
 static mut LOADED_IMAGE: *mut loaded_image::Protocol = core::ptr::null_mut();
 
 fn init_dummy_protocol(image_handle: efi::Handle) -> efi::Status {
@@ -201,30 +268,9 @@ fn init_dummy_protocol(image_handle: efi::Handle) -> efi::Status {
         return status;
     }
 
-    // create protocol?
-
     unsafe { (&mut *LOADED_IMAGE).unload = efi_unload };
 
     efi::Status::SUCCESS
-
-    /*
-        // Install our protocol interface
-        // This is needed to keep our driver loaded
-        DummyProtocalData dummy = { 0 };
-        status = LibInstallProtocolInterfaces(
-          &ImageHandle, &ProtocolGuid,
-          &dummy, NULL);
-
-        // Return if interface failed to register
-        if (EFI_ERROR(status))
-        {
-            Print(L"Can't register interface: %d\n", status);
-            return status;
-        }
-
-        // Set our image unload routine
-        LoadedImage->Unload = (EFI_IMAGE_UNLOAD)efi_unload;
-    */
 }
 
 fn test_phys_read() {
@@ -259,16 +305,35 @@ pub extern "C" fn main(
     // setup system_table
     unsafe { SYSTEM_TABLE = MaybeUninit::new(raw_system_table.read()) };
 
-    // setup allocator
-    init_allocator();
-
     info!("enter main()");
 
     init_dummy_protocol(image_handle);
 
+    if let Err(err) = images::capture_at_load() {
+        warn!("unable to record loaded images: {}", err);
+    }
+    if let Err(err) = firmware_tables::init() {
+        warn!("unable to locate firmware tables: {}", err);
+    }
+    if let Err(err) = pci::init() {
+        warn!("unable to enumerate pci devices: {}", err);
+    }
+
+    // command stacks are allocated before the memory map is retrieved so they are part of the identity mapping
+    let status = percpu::init_stacks();
+    if status.is_error() {
+        error!("unable to allocate command stacks: {:#x}", status.as_usize());
+        release_resources();
+        return status;
+    }
+
     // TODO: move to exit boot
     let mem_maps = unsafe { &mut EFI_MEM_MAPS };
-    mem_maps.load_maps(boot_services()); // TODO: temp
+    if let Err(err) = mem_maps.load_maps(boot_services()) {
+        error!("mem_maps could not be retrieved: {}", err);
+        release_resources();
+        return efi::Status::ABORTED;
+    }
     let identity_page_table = unsafe { &mut IDENTITY_PAGE_TABLE };
     match identity_page_table.create_identity_mapping(mem_maps) {
         Ok(_) => {
@@ -276,21 +341,28 @@ pub extern "C" fn main(
         }
         Err(err) => {
             error!("unable to create identity mapping: {}", err);
+            release_resources();
             return efi::Status::ABORTED;
         }
     }
-    unsafe { IDENTITY_PAGE_TABLE_BASE = identity_page_table.dtb_addr() };
+
+    // nmis taken inside the identity mapping are lost without the apic in xapic mode
+    if let Err(err) = apic::init(identity_page_table) {
+        warn!("unable to map the local apic: {}", err);
+    }
+    if let Err(err) = framebuffer::init(identity_page_table) {
+        warn!("unable to record the framebuffer: {}", err);
+    }
     //test_phys_read();
 
     // Register to events relevant for runtime drivers.
-    let mut event_virtual_address: base::Event = core::ptr::null_mut();
     let mut status = (boot_services().create_event_ex)(
         efi::EVT_NOTIFY_SIGNAL,
         efi::TPL_CALLBACK,
         Some(handle_set_virtual_address_map),
         runtime_services() as *const _ as *mut c_void,
         &efi::EVENT_GROUP_VIRTUAL_ADDRESS_CHANGE,
-        event_virtual_address.borrow_mut(),
+        unsafe { &mut EVENT_VIRTUAL_ADDRESS },
     );
 
     if status.is_error() {
@@ -298,17 +370,17 @@ pub extern "C" fn main(
             "creating VIRTUAL_ADDRESS_CHANGE event failed: {:#x}",
             status.as_usize()
         );
+        release_resources();
         return status;
     }
 
-    let mut event_boot_services: base::Event = core::ptr::null_mut();
     status = (boot_services().create_event_ex)(
         efi::EVT_NOTIFY_SIGNAL,
         efi::TPL_CALLBACK,
         Some(handle_exit_boot_services),
         runtime_services() as *const _ as *mut c_void,
         &efi::EVENT_GROUP_EXIT_BOOT_SERVICES,
-        event_boot_services.borrow_mut(),
+        unsafe { &mut EVENT_BOOT_SERVICES },
     );
 
     if status.is_error() {
@@ -316,13 +388,20 @@ pub extern "C" fn main(
             "creating EXIT_BOOT_SERVICES event failed: {:#x}",
             status.as_usize()
         );
+        release_resources();
         return status;
     }
 
-    // Your runtime driver initialization. If the initialization fails, manually close the previously
-    // created events with:
-    // (boot_services().close_event)(event_virtual_address);
-    // (boot_services().close_event)(event_boot_services);
+    // installed last, a failing driver entry point gets unloaded and must not leave the protocol behind
+    let status = protocol::install(image_handle);
+    if status.is_error() {
+        error!(
+            "unable to install memflow protocol: {:#x}",
+            status.as_usize()
+        );
+        release_resources();
+        return status;
+    }
 
     info!("memflow efi runtime driver has been initialized.");
 
@@ -333,68 +412,3 @@ pub extern "C" fn main(
 
     efi::Status::SUCCESS
 }
-
-#[macro_use]
-extern crate alloc_no_stdlib;
-use alloc_no_stdlib::{
-    bzero, uninitialized, AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut,
-    StackAllocator,
-};
-
-use ::core::alloc::{GlobalAlloc, Layout};
-
-// allocator
-declare_stack_allocator_struct!(GlobalAllocatedFreelist, 16, global);
-define_allocator_memory_pool!(16, u8, [0; 1024 * 1024 * 25], global, global_buffer);
-
-static mut AGS: MaybeUninit<StackAllocator<u8, GlobalAllocatedFreelist<u8>>> =
-    MaybeUninit::uninit();
-fn ags_mut() -> &'static mut StackAllocator<'static, u8, GlobalAllocatedFreelist<'static, u8>> {
-    unsafe { &mut *AGS.as_mut_ptr() }
-}
-
-fn init_allocator() {
-    unsafe { AGS = MaybeUninit::new(GlobalAllocatedFreelist::<u8>::new_allocator(bzero)) };
-    unsafe {
-        bind_global_buffers_to_allocator!((&mut *AGS.as_mut_ptr()), global_buffer, u8);
-    }
-}
-
-pub struct EfiAllocator;
-
-unsafe impl GlobalAlloc for EfiAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        {
-            let size = layout.size();
-            let align = layout.align();
-
-            if align > 8 {
-                // allocate more space for alignment
-                let ptr = ags_mut().alloc_cell(size + align).as_mut_ptr();
-
-                // calculate align offset
-                let mut offset = ptr.align_offset(align);
-                if offset == 0 {
-                    offset = align;
-                }
-                let return_ptr = ptr.add(offset);
-                // store allocated pointer before the struct
-                (return_ptr.cast::<*mut u8>()).sub(1).write(ptr);
-                return_ptr
-            } else {
-                ags_mut().alloc_cell(size).as_mut_ptr()
-            }
-        }
-    }
-
-    unsafe fn dealloc(&self, mut ptr: *mut u8, layout: Layout) {
-        if layout.align() > 8 {
-            ptr = (ptr as *const *mut u8).sub(1).read();
-        }
-        let buf = unsafe { core::slice::from_raw_parts_mut(ptr, layout.size()) };
-        ags_mut().free_cell(AllocatedStackMemory { mem: buf })
-    }
-}
-
-#[global_allocator]
-static ALLOCATOR: EfiAllocator = EfiAllocator;
@@ -1,6 +1,5 @@
 use core::slice::Iter;
 
-use ::alloc::{string::String, vec::Vec};
 use ::r_efi::{
     protocols::file::ProtocolOpen,
     protocols::*,
@@ -8,31 +7,87 @@ use ::r_efi::{
     system::{RuntimeSetVariable, TPL_HIGH_LEVEL},
     *,
 };
-use alloc::format;
-use r_efi::system::LOADER_DATA;
+use r_efi::system::{
+    ALLOCATE_ANY_PAGES, BOOT_SERVICES_CODE, BOOT_SERVICES_DATA, CONVENTIONAL_MEMORY, LOADER_CODE,
+    LOADER_DATA, RUNTIME_SERVICES_DATA,
+};
+
+use crate::{framebuffer::is_framebuffer, pci::is_device_memory};
+
+/// Maximum number of memory descriptors we are willing to store.
+const MAX_MEM_MAPS: usize = 4096;
+
+/// Additional descriptors to account for changes in the memory map after it has been sized.
+const MEM_MAPS_SLACK: usize = 32;
+
+/// Memory types the OS is free to use after `ExitBootServices`, e.g. for its page tables.
+pub const OS_MEMORY_TYPE_MASK: u64 = 1 << LOADER_CODE
+    | 1 << LOADER_DATA
+    | 1 << BOOT_SERVICES_CODE
+    | 1 << BOOT_SERVICES_DATA
+    | 1 << CONVENTIONAL_MEMORY;
 
 /// Reads and stores the memory mappings returned by EFI boot services
 pub struct EfiMemMaps {
-    // staticalloy allocate memory in binary
-    mem_maps: [MemoryDescriptor; 1024],
+    // runtime memory sized from the memory map on load
+    mem_maps: *mut MemoryDescriptor,
+    capacity: usize,
     num_mem_maps: usize,
 }
 
 impl EfiMemMaps {
     pub const fn new() -> Self {
         Self {
-            mem_maps: [MemoryDescriptor {
-                r#type: 0,
-                physical_start: 0,
-                virtual_start: 0,
-                number_of_pages: 0,
-                attribute: 0,
-            }; 1024],
+            mem_maps: core::ptr::null_mut(),
+            capacity: 0,
             num_mem_maps: 0,
         }
     }
 
-    pub fn load_maps(&mut self, boot_services: &efi::BootServices) -> Result<(), String> {
+    /// Makes sure the descriptor storage can hold at least `num_mem_maps` entries.
+    ///
+    /// The storage is allocated as runtime memory so it is still accessible after `ExitBootServices`.
+    fn reserve(
+        &mut self,
+        boot_services: &efi::BootServices,
+        num_mem_maps: usize,
+    ) -> Result<(), &'static str> {
+        if num_mem_maps <= self.capacity {
+            return Ok(());
+        }
+
+        let capacity = (num_mem_maps + MEM_MAPS_SLACK).min(MAX_MEM_MAPS);
+        if capacity < num_mem_maps {
+            error!("found {} memory maps, out of memory.", num_mem_maps);
+            return Err("too many memory maps");
+        }
+
+        let pages = (capacity * core::mem::size_of::<MemoryDescriptor>() + 0xfff) / 0x1000;
+        let mut addr = 0u64;
+        let status = (boot_services.allocate_pages)(
+            ALLOCATE_ANY_PAGES,
+            RUNTIME_SERVICES_DATA,
+            pages,
+            &mut addr,
+        );
+        if status != efi::Status::SUCCESS {
+            error!("allocate_pages failed with status: `{:?}`", status);
+            return Err("allocate_pages failed");
+        }
+
+        if !self.mem_maps.is_null() {
+            let old_pages =
+                (self.capacity * core::mem::size_of::<MemoryDescriptor>() + 0xfff) / 0x1000;
+            (boot_services.free_pages)(self.mem_maps as u64, old_pages);
+        }
+
+        self.mem_maps = addr as *mut MemoryDescriptor;
+        self.capacity = capacity;
+        self.num_mem_maps = 0;
+        Ok(())
+    }
+
+    pub fn load_maps(&mut self, boot_services: &efi::BootServices) -> Result<(), &'static str> {
         let mut tmp_mem_maps = [0u8; 1];
         let mut mem_maps_size = 0usize;
         let mut map_key = 0usize;
@@ -48,11 +103,12 @@ impl EfiMemMaps {
             &mut descriptor_version as *mut _,
         );
         if status != efi::Status::BUFFER_TOO_SMALL {
-            return Err(format!(
+            error!(
                 "get_memory_map returned status `{:?}` but `{:?}` was expected",
                 status,
                 efi::Status::BUFFER_TOO_SMALL
-            ));
+            );
+            return Err("get_memory_map did not return the required buffer size");
         }
 
         mem_maps_size += 0x1000; // #define EFI_PAGE_SIZE SIZE_4KB
@@ -61,6 +117,9 @@ impl EfiMemMaps {
             mem_maps_size
         );
 
+        // reserve storage up front as allocating it alters the memory map
+        self.reserve(boot_services, mem_maps_size / descriptor_size)?;
+
         // allocate required buffer and convert it into a slice
         let mut mem_maps_ptr = core::ptr::null_mut();
         let status = (boot_services.allocate_pool)(
@@ -69,7 +128,8 @@ impl EfiMemMaps {
             &mut mem_maps_ptr as *mut *mut _ as *mut *mut _,
         );
         if status != efi::Status::SUCCESS {
-            return Err(format!("allocate_pool failed with status: `{:?}`", status));
+            error!("allocate_pool failed with status: `{:?}`", status);
+            return Err("allocate_pool failed");
         }
 
         // retrieve final memory mappings
@@ -81,7 +141,8 @@ impl EfiMemMaps {
             &mut descriptor_version as *mut _,
         );
         if status != efi::Status::SUCCESS {
-            return Err(format!("get_memory_map failed with status: `{:?}`", status));
+            error!("get_memory_map failed with status: `{:?}`", status);
+            return Err("get_memory_map failed");
         }
 
         // convert this oddity in a regular rust slice
@@ -89,13 +150,13 @@ impl EfiMemMaps {
 
         let num_mem_maps = mem_maps_size / descriptor_size;
         info!("found a total of {} mem_maps.", num_mem_maps);
-        if self.num_mem_maps > 1024 {
-            return Err(format!(
-                "found {} memory maps, out of memory.",
-                self.num_mem_maps
-            ));
+        if num_mem_maps > self.capacity {
+            (boot_services.free_pool)(mem_maps_ptr);
+            error!("found {} memory maps, out of memory.", num_mem_maps);
+            return Err("too many memory maps");
         }
         self.num_mem_maps = num_mem_maps;
+        let mem_maps = self.as_mut_slice();
 
         let mut mem_map: &mut MemoryDescriptor = unsafe { core::mem::transmute(mem_maps_ptr) };
         for i in 0..num_mem_maps {
@@ -106,11 +167,11 @@ impl EfiMemMaps {
                 mem_map.physical_start,
                 mem_map.number_of_pages
             );
-            self.mem_maps[i].r#type = mem_map.r#type;
-            self.mem_maps[i].virtual_start = mem_map.virtual_start;
-            self.mem_maps[i].physical_start = mem_map.physical_start;
-            self.mem_maps[i].number_of_pages = mem_map.number_of_pages;
-            self.mem_maps[i].attribute = mem_map.attribute;
+            mem_maps[i].r#type = mem_map.r#type;
+            mem_maps[i].virtual_start = mem_map.virtual_start;
+            mem_maps[i].physical_start = mem_map.physical_start;
+            mem_maps[i].number_of_pages = mem_map.number_of_pages;
+            mem_maps[i].attribute = mem_map.attribute;
 
             mem_map = unsafe {
                 core::mem::transmute((mem_map as *mut _ as usize + descriptor_size) as *mut u8)
@@ -119,20 +180,55 @@ impl EfiMemMaps {
 
         let status = (boot_services.free_pool)(mem_maps_ptr);
         if status != efi::Status::SUCCESS {
-            return Err(format!("free_pool failed with status: `{:?}`", status));
+            error!("free_pool failed with status: `{:?}`", status);
+            return Err("free_pool failed");
         }
 
         Ok(())
     }
 
+    /// Frees the storage of the memory maps, this must only be called while unloading the driver.
+    pub fn release(&mut self, boot_services: &efi::BootServices) {
+        if !self.mem_maps.is_null() {
+            let pages = (self.capacity * core::mem::size_of::<MemoryDescriptor>() + 0xfff) / 0x1000;
+            (boot_services.free_pages)(self.mem_maps as u64, pages);
+        }
+        self.mem_maps = core::ptr::null_mut();
+        self.capacity = 0;
+        self.num_mem_maps = 0;
+    }
+
     pub fn len(&self) -> usize {
         self.num_mem_maps
     }
 
+    fn as_slice(&self) -> &[MemoryDescriptor] {
+        if self.mem_maps.is_null() {
+            &[]
+        } else {
+            unsafe { core::slice::from_raw_parts(self.mem_maps, self.num_mem_maps) }
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [MemoryDescriptor] {
+        if self.mem_maps.is_null() {
+            &mut []
+        } else {
+            unsafe { core::slice::from_raw_parts_mut(self.mem_maps, self.num_mem_maps) }
+        }
+    }
+
     /// Checks if the given base_addr is mapped.
+    ///
+    /// Ranges decoded by pci devices are never considered mapped, except for the framebuffer.
     pub fn is_mapped(&self, base_addr: u64) -> bool {
-        for i in 0..self.num_mem_maps {
-            let mem_map = &self.mem_maps[i];
+        if is_framebuffer(base_addr) {
+            return true;
+        }
+        if is_device_memory(base_addr) {
+            return false;
+        }
+        for mem_map in self.as_slice().iter() {
             if mem_map.r#type == 7
                 && mem_map.physical_start <= base_addr
                 && base_addr < mem_map.physical_start + mem_map.number_of_pages * 0x1000
@@ -144,8 +240,37 @@ impl EfiMemMaps {
         false
     }
 
+    /// Returns the memory map containing `addr`.
+    pub fn descriptor(&self, addr: u64) -> Option<&MemoryDescriptor> {
+        self.as_slice().iter().find(|m| {
+            m.physical_start <= addr && addr < m.physical_start + m.number_of_pages * 0x1000
+        })
+    }
+
+    /// Returns the type of the memory map containing `addr`.
+    pub fn memory_type(&self, addr: u64) -> Option<u32> {
+        self.descriptor(addr).map(|m| m.r#type)
+    }
+
+    /// Checks if `addr` lies in a memory map whose type is set in `type_mask`.
+    ///
+    /// An empty mask falls back to the memory considered by `is_mapped`.
+    /// Ranges decoded by pci devices are excluded regardless of their type.
+    pub fn is_type_mapped(&self, addr: u64, type_mask: u64) -> bool {
+        if type_mask == 0 {
+            return self.is_mapped(addr);
+        }
+        if is_device_memory(addr) {
+            return false;
+        }
+        match self.memory_type(addr) {
+            Some(r#type) if r#type < 64 => type_mask & (1 << r#type) != 0,
+            _ => false,
+        }
+    }
+
     pub fn iter(&self) -> Iter<MemoryDescriptor> {
-        self.mem_maps[0..self.num_mem_maps].iter()
+        self.as_slice().iter()
     }
 }
 
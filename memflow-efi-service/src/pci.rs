@@ -0,0 +1,338 @@
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use ::r_efi::*;
+
+use crate::boot_services;
+
+/// EFI_PCI_IO_PROTOCOL_GUID, not part of r-efi.
+const PCI_IO_PROTOCOL_GUID: efi::Guid = efi::Guid::from_fields(
+    0x4cf5b200,
+    0x68b8,
+    0x4ca5,
+    0x9e,
+    0xec,
+    &[0xb2, 0x3e, 0x3f, 0x50, 0x02, 0x9a],
+);
+
+/// EfiPciIoWidthUint32
+const PCI_IO_WIDTH_UINT32: u32 = 2;
+
+// ACPI address space descriptors returned by GetBarAttributes
+const ACPI_QWORD_ADDRESS_SPACE_DESCRIPTOR: u8 = 0x8a;
+const ACPI_ADDRESS_SPACE_TYPE_MEM: u8 = 0;
+const ACPI_ADDRESS_SPACE_TYPE_IO: u8 = 1;
+const ACPI_SPECIFIC_FLAG_PREFETCHABLE: u8 = 0x06;
+
+/// Offsets into a qword address space descriptor.
+const DESC_RESOURCE_TYPE: usize = 3;
+const DESC_SPECIFIC_FLAGS: usize = 5;
+const DESC_RANGE_MIN: usize = 14;
+const DESC_LENGTH: usize = 38;
+
+/// Maximum number of pci functions that are recorded.
+pub const MAX_PCI_DEVICES: usize = 256;
+
+/// Number of base address registers of a type 0 header.
+pub const PCI_MAX_BARS: usize = 6;
+
+/// The bar decodes memory, otherwise it decodes io ports.
+pub const PCI_BAR_MEMORY: u32 = 1 << 0;
+pub const PCI_BAR_PREFETCHABLE: u32 = 1 << 1;
+
+type PciIoConfigRead = eficall! {fn(
+    *mut PciIoProtocol,
+    u32,
+    u32,
+    usize,
+    *mut c_void,
+) -> efi::Status};
+
+type PciIoGetLocation = eficall! {fn(
+    *mut PciIoProtocol,
+    *mut usize,
+    *mut usize,
+    *mut usize,
+    *mut usize,
+) -> efi::Status};
+
+type PciIoGetBarAttributes = eficall! {fn(
+    *mut PciIoProtocol,
+    u8,
+    *mut u64,
+    *mut *mut c_void,
+) -> efi::Status};
+
+/// EFI_PCI_IO_PROTOCOL, only the functions we call are typed.
+#[repr(C)]
+struct PciIoProtocol {
+    poll_mem: *mut c_void,
+    poll_io: *mut c_void,
+    mem: [*mut c_void; 2],
+    io: [*mut c_void; 2],
+    pci_read: PciIoConfigRead,
+    pci_write: *mut c_void,
+    copy_mem: *mut c_void,
+    map: *mut c_void,
+    unmap: *mut c_void,
+    allocate_buffer: *mut c_void,
+    free_buffer: *mut c_void,
+    flush: *mut c_void,
+    get_location: PciIoGetLocation,
+    attributes: *mut c_void,
+    get_bar_attributes: PciIoGetBarAttributes,
+    set_bar_attributes: *mut c_void,
+    rom_size: u64,
+    rom_image: *mut c_void,
+}
+
+/// A range decoded by a base address register, unused bars have a size of 0.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PciBar {
+    pub base: u64,
+    pub size: u64,
+    /// `PCI_BAR_MEMORY` and `PCI_BAR_PREFETCHABLE` bits.
+    pub flags: u32,
+    pub reserved: u32,
+}
+
+/// A pci function as seen through its pci io protocol.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PciDevice {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub reserved: [u8; 3],
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// Revision id in the low byte followed by the class code.
+    pub class_revision: u32,
+    pub bars: [PciBar; PCI_MAX_BARS],
+}
+
+const EMPTY_BAR: PciBar = PciBar {
+    base: 0,
+    size: 0,
+    flags: 0,
+    reserved: 0,
+};
+
+const EMPTY_DEVICE: PciDevice = PciDevice {
+    segment: 0,
+    bus: 0,
+    device: 0,
+    function: 0,
+    reserved: [0; 3],
+    vendor_id: 0,
+    device_id: 0,
+    class_revision: 0,
+    bars: [EMPTY_BAR; PCI_MAX_BARS],
+};
+
+// written once while boot services are available and read-only afterwards
+static mut PCI_DEVICES: [PciDevice; MAX_PCI_DEVICES] = [EMPTY_DEVICE; MAX_PCI_DEVICES];
+static PCI_DEVICE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A memory range decoded by a bar, `end` is exclusive.
+#[derive(Clone, Copy)]
+struct DeviceRange {
+    base: u64,
+    end: u64,
+}
+
+// memory ranges of all bars sorted by base with overlapping ranges merged, built together with PCI_DEVICES
+static mut DEVICE_RANGES: [DeviceRange; MAX_PCI_DEVICES * PCI_MAX_BARS] =
+    [DeviceRange { base: 0, end: 0 }; MAX_PCI_DEVICES * PCI_MAX_BARS];
+static DEVICE_RANGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Records all pci functions and the ranges decoded by their bars.
+///
+/// Bars are taken from the resources assigned by the firmware, they are never sized by writing to the device.
+/// This has to be called while boot services are available.
+pub fn init() -> Result<(), &'static str> {
+    let mut count = 0;
+    let mut handles: *mut efi::Handle = core::ptr::null_mut();
+    let mut guid = PCI_IO_PROTOCOL_GUID;
+    let status = (boot_services().locate_handle_buffer)(
+        efi::BY_PROTOCOL,
+        &mut guid,
+        core::ptr::null_mut(),
+        &mut count,
+        &mut handles,
+    );
+    if status == efi::Status::NOT_FOUND {
+        info!("no pci devices found");
+        return Ok(());
+    } else if status.is_error() || handles.is_null() {
+        error!("locate_handle_buffer failed with status: `{:?}`", status);
+        return Err("unable to locate pci io handles");
+    }
+
+    if count > MAX_PCI_DEVICES {
+        warn!(
+            "{} pci devices, only the first {} are recorded",
+            count, MAX_PCI_DEVICES
+        );
+    }
+
+    // the handle buffer is allocated from pool on our behalf
+    let located = unsafe { core::slice::from_raw_parts(handles, count) };
+    let devices = unsafe { &mut PCI_DEVICES };
+    let mut recorded = 0;
+    for handle in located.iter().take(MAX_PCI_DEVICES) {
+        let mut pci_io: *mut PciIoProtocol = core::ptr::null_mut();
+        let status = (boot_services().handle_protocol)(
+            *handle,
+            &mut guid,
+            &mut pci_io as *mut _ as *mut *mut c_void,
+        );
+        if status.is_error() || pci_io.is_null() {
+            continue;
+        }
+
+        if let Some(device) = unsafe { read_device(pci_io) } {
+            devices[recorded] = device;
+            recorded += 1;
+        }
+    }
+    (boot_services().free_pool)(handles as *mut c_void);
+
+    PCI_DEVICE_COUNT.store(recorded, Ordering::SeqCst);
+    build_device_ranges(&devices[..recorded]);
+
+    info!("recorded {} pci devices", recorded);
+    Ok(())
+}
+
+/// Sorts the memory ranges of all bars and merges overlapping ones for `is_device_memory`.
+fn build_device_ranges(devices: &[PciDevice]) {
+    let ranges = unsafe { &mut DEVICE_RANGES };
+    let mut count = 0;
+    for bar in devices.iter().flat_map(|device| device.bars.iter()) {
+        if bar.flags & PCI_BAR_MEMORY != 0 && bar.size != 0 {
+            ranges[count] = DeviceRange {
+                base: bar.base,
+                end: bar.base.saturating_add(bar.size),
+            };
+            count += 1;
+        }
+    }
+
+    let ranges = &mut ranges[..count];
+    ranges.sort_unstable_by_key(|range| range.base);
+
+    let mut merged = 0;
+    for i in 0..ranges.len() {
+        let range = ranges[i];
+        if merged > 0 && range.base <= ranges[merged - 1].end {
+            ranges[merged - 1].end = ranges[merged - 1].end.max(range.end);
+        } else {
+            ranges[merged] = range;
+            merged += 1;
+        }
+    }
+    DEVICE_RANGE_COUNT.store(merged, Ordering::SeqCst);
+}
+
+unsafe fn read_device(pci_io: *mut PciIoProtocol) -> Option<PciDevice> {
+    let mut device = EMPTY_DEVICE;
+
+    let (mut segment, mut bus, mut dev, mut function) = (0usize, 0usize, 0usize, 0usize);
+    let status = ((*pci_io).get_location)(pci_io, &mut segment, &mut bus, &mut dev, &mut function);
+    if status.is_error() {
+        return None;
+    }
+    device.segment = segment as u16;
+    device.bus = bus as u8;
+    device.device = dev as u8;
+    device.function = function as u8;
+
+    let mut header = [0u32; 3];
+    let status = ((*pci_io).pci_read)(
+        pci_io,
+        PCI_IO_WIDTH_UINT32,
+        0,
+        header.len(),
+        header.as_mut_ptr() as *mut c_void,
+    );
+    if status.is_error() {
+        return None;
+    }
+    device.vendor_id = header[0] as u16;
+    device.device_id = (header[0] >> 16) as u16;
+    device.class_revision = header[2];
+
+    for (index, bar) in device.bars.iter_mut().enumerate() {
+        *bar = read_bar(pci_io, index as u8).unwrap_or(EMPTY_BAR);
+    }
+    Some(device)
+}
+
+/// Reads the range assigned to a bar from the address space descriptors of GetBarAttributes.
+unsafe fn read_bar(pci_io: *mut PciIoProtocol, index: u8) -> Option<PciBar> {
+    let mut resources: *mut c_void = core::ptr::null_mut();
+    let status =
+        ((*pci_io).get_bar_attributes)(pci_io, index, core::ptr::null_mut(), &mut resources);
+    if status.is_error() || resources.is_null() {
+        return None;
+    }
+
+    let mut bar = None;
+    let mut desc = resources as *const u8;
+    while *desc == ACPI_QWORD_ADDRESS_SPACE_DESCRIPTOR {
+        let len = (desc.add(1) as *const u16).read_unaligned() as usize + 3;
+        let size = (desc.add(DESC_LENGTH) as *const u64).read_unaligned();
+        let flags = match *desc.add(DESC_RESOURCE_TYPE) {
+            ACPI_ADDRESS_SPACE_TYPE_MEM
+                if *desc.add(DESC_SPECIFIC_FLAGS) & ACPI_SPECIFIC_FLAG_PREFETCHABLE
+                    == ACPI_SPECIFIC_FLAG_PREFETCHABLE =>
+            {
+                Some(PCI_BAR_MEMORY | PCI_BAR_PREFETCHABLE)
+            }
+            ACPI_ADDRESS_SPACE_TYPE_MEM => Some(PCI_BAR_MEMORY),
+            ACPI_ADDRESS_SPACE_TYPE_IO => Some(0),
+            _ => None,
+        };
+        if let (Some(flags), true) = (flags, size > 0) {
+            bar = Some(PciBar {
+                base: (desc.add(DESC_RANGE_MIN) as *const u64).read_unaligned(),
+                size,
+                flags,
+                reserved: 0,
+            });
+            break;
+        }
+        desc = desc.add(len);
+    }
+
+    // the descriptors are allocated from pool on our behalf
+    (boot_services().free_pool)(resources);
+    bar
+}
+
+/// Forgets all recorded pci devices, used when unloading the driver.
+pub fn reset() {
+    DEVICE_RANGE_COUNT.store(0, Ordering::SeqCst);
+    PCI_DEVICE_COUNT.store(0, Ordering::SeqCst);
+}
+
+pub fn pci_device_count() -> usize {
+    PCI_DEVICE_COUNT.load(Ordering::SeqCst)
+}
+
+/// Returns the recorded pci devices.
+pub fn pci_devices() -> &'static [PciDevice] {
+    unsafe { &PCI_DEVICES[..pci_device_count()] }
+}
+
+/// Checks if `addr` lies in a memory range decoded by a pci device.
+pub fn is_device_memory(addr: u64) -> bool {
+    let ranges = unsafe { &DEVICE_RANGES[..DEVICE_RANGE_COUNT.load(Ordering::SeqCst)] };
+    match ranges.partition_point(|range| range.base <= addr) {
+        0 => false,
+        index => addr < ranges[index - 1].end,
+    }
+}
@@ -0,0 +1,100 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use ::r_efi::*;
+use r_efi::system::{ALLOCATE_ANY_PAGES, RUNTIME_SERVICES_DATA};
+
+use crate::boot_services;
+
+/// Maximum number of cpus that can execute commands.
+pub const MAX_CPUS: usize = 64;
+
+/// Size of the stack each cpu uses while executing a command.
+const STACK_SIZE: usize = 0x10000;
+
+const NO_CPU: u32 = u32::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED_CPU: AtomicU32 = AtomicU32::new(NO_CPU);
+
+/// Maps cpu slots to apic ids, slots are claimed on first use.
+static CPU_APIC_IDS: [AtomicU32; MAX_CPUS] = [UNUSED_CPU; MAX_CPUS];
+
+/// Physical address of the stacks.
+static mut STACKS_BASE: u64 = 0;
+
+/// Allocates the command stacks for all cpus.
+///
+/// The stacks are runtime memory, thus they are also part of the identity mapping.
+pub fn init_stacks() -> efi::Status {
+    let mut base = 0u64;
+    let status = (boot_services().allocate_pages)(
+        ALLOCATE_ANY_PAGES,
+        RUNTIME_SERVICES_DATA,
+        MAX_CPUS * STACK_SIZE / 0x1000,
+        &mut base,
+    );
+    if status.is_error() {
+        return status;
+    }
+
+    info!("allocated {} command stacks at {:x}", MAX_CPUS, base);
+    unsafe { STACKS_BASE = base };
+    efi::Status::SUCCESS
+}
+
+/// Frees the command stacks, this must only be called while unloading the driver.
+pub fn release_stacks() {
+    let base = unsafe { core::mem::replace(&mut STACKS_BASE, 0) };
+    if base != 0 {
+        (boot_services().free_pages)(base, MAX_CPUS * STACK_SIZE / 0x1000);
+    }
+}
+
+/// Returns the apic id of the current cpu.
+pub fn apic_id() -> u32 {
+    unsafe {
+        // prefer the x2apic id from the extended topology leaf
+        if __cpuid(0).eax >= 0xb {
+            let leaf = __cpuid_count(0xb, 0);
+            if leaf.ebx != 0 {
+                return leaf.edx;
+            }
+        }
+        __cpuid(1).ebx >> 24
+    }
+}
+
+/// Returns the slot of the current cpu, claiming a new one on first use.
+///
+/// `None` is returned when more than `MAX_CPUS` cpus issued commands.
+pub fn cpu_index() -> Option<usize> {
+    let apic_id = apic_id();
+    for (i, slot) in CPU_APIC_IDS.iter().enumerate() {
+        match slot.compare_exchange(NO_CPU, apic_id, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return Some(i),
+            Err(id) if id == apic_id => return Some(i),
+            Err(_) => (),
+        }
+    }
+    None
+}
+
+/// Returns the physical address of the top of the stack for the given cpu slot.
+pub fn stack_top(cpu_index: usize) -> Option<u64> {
+    let base = unsafe { STACKS_BASE };
+    if base == 0 || cpu_index >= MAX_CPUS {
+        return None;
+    }
+    Some(base + ((cpu_index + 1) * STACK_SIZE) as u64)
+}
+
+/// Returns true if the current cpu runs on one of the command stacks, i.e. inside the identity window.
+pub fn on_command_stack() -> bool {
+    let base = unsafe { STACKS_BASE };
+    let rsp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    base != 0 && rsp >= base && rsp - base < (MAX_CPUS * STACK_SIZE) as u64
+}
@@ -0,0 +1,201 @@
+use core::ffi::c_void;
+
+use ::r_efi::*;
+use r_efi::system::MemoryDescriptor;
+
+use crate::{
+    boot_services, boot_services_available, fault::catch_faults, hooks::WINDOW_LOCK, EFI_MEM_MAPS,
+};
+
+/// Guid of the `MemflowProtocol` installed on our image handle.
+pub const MEMFLOW_PROTOCOL_GUID: efi::Guid = efi::Guid::from_fields(
+    0xdd2ea497,
+    0xf978,
+    0x45c5,
+    0x9c,
+    0xd5,
+    &[0x73, 0x55, 0x46, 0x19, 0xb3, 0x40],
+);
+
+pub const MEMFLOW_PROTOCOL_REVISION: u64 = 1;
+
+/// Reads `len` bytes of physical memory at the given address into the buffer.
+///
+/// Pages that are not readable are zero filled, `ACCESS_DENIED` is returned if none of them are.
+pub type ProtocolReadPhys = eficall! {fn(
+    *mut MemflowProtocol,
+    u64,
+    *mut c_void,
+    usize,
+) -> efi::Status};
+
+/// Writes `len` bytes from the buffer to physical memory at the given address.
+///
+/// Nothing is written unless all pages are writable. Only available with the `writes` feature.
+pub type ProtocolWritePhys = eficall! {fn(
+    *mut MemflowProtocol,
+    u64,
+    *const c_void,
+    usize,
+) -> efi::Status};
+
+/// Copies the memory map containing the given physical address into the descriptor.
+pub type ProtocolQueryMap = eficall! {fn(
+    *mut MemflowProtocol,
+    u64,
+    *mut MemoryDescriptor,
+) -> efi::Status};
+
+/// Physical memory access for UEFI applications running before `ExitBootServices`.
+///
+/// The same memory policy as for the commands issued through SetVariable applies.
+#[repr(C)]
+pub struct MemflowProtocol {
+    pub revision: u64,
+    pub read_phys: ProtocolReadPhys,
+    pub write_phys: ProtocolWritePhys,
+    pub query_map: ProtocolQueryMap,
+}
+
+static mut MEMFLOW_PROTOCOL: MemflowProtocol = MemflowProtocol {
+    revision: MEMFLOW_PROTOCOL_REVISION,
+    read_phys,
+    write_phys,
+    query_map,
+};
+
+/// Installs the `MemflowProtocol` on our image handle.
+///
+/// The protocol also keeps the driver resident.
+pub fn install(image_handle: efi::Handle) -> efi::Status {
+    let mut handle = image_handle;
+    let mut guid = MEMFLOW_PROTOCOL_GUID;
+    (boot_services().install_protocol_interface)(
+        &mut handle,
+        &mut guid,
+        efi::NATIVE_INTERFACE,
+        unsafe { &mut MEMFLOW_PROTOCOL } as *mut _ as *mut c_void,
+    )
+}
+
+/// Removes the `MemflowProtocol` from our image handle.
+///
+/// Fails if the protocol is still opened by another driver.
+pub fn uninstall(image_handle: efi::Handle) -> efi::Status {
+    let mut guid = MEMFLOW_PROTOCOL_GUID;
+    (boot_services().uninstall_protocol_interface)(
+        image_handle,
+        &mut guid,
+        unsafe { &mut MEMFLOW_PROTOCOL } as *mut _ as *mut c_void,
+    )
+}
+
+/// Iterates the pages touched by `addr`..`addr + len` as (address, offset, length) tuples.
+fn pages(addr: u64, len: usize) -> impl Iterator<Item = (u64, usize, usize)> {
+    let mut offs = 0usize;
+    core::iter::from_fn(move || {
+        if offs >= len {
+            return None;
+        }
+        let page_addr = addr + offs as u64;
+        let page_len = (0x1000 - (page_addr % 0x1000) as usize).min(len - offs);
+        let page = (page_addr, offs, page_len);
+        offs += page_len;
+        Some(page)
+    })
+}
+
+/// Runs `f` under the window lock with faults caught, boot services memory is identity mapped by the firmware.
+fn access_phys(addr: u64, len: usize, f: impl FnOnce() -> efi::Status) -> efi::Status {
+    if !boot_services_available() {
+        return efi::Status::UNSUPPORTED;
+    }
+    if len == 0 || addr.checked_add(len as u64).is_none() {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    let _lock = WINDOW_LOCK.lock();
+    match catch_faults(f) {
+        Ok(status) => status,
+        Err(fault) => {
+            error!("protocol access at {:x} aborted: {:x?}", addr, fault);
+            efi::Status::ABORTED
+        }
+    }
+}
+
+eficall! {fn read_phys(
+    _this: *mut MemflowProtocol,
+    addr: u64,
+    buffer: *mut c_void,
+    len: usize,
+) -> efi::Status {
+    if buffer.is_null() {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    access_phys(addr, len, || {
+        let mem_maps = unsafe { &EFI_MEM_MAPS };
+        let mut result = efi::Status::ACCESS_DENIED;
+        for (page_addr, offs, page_len) in pages(addr, len) {
+            let dst = unsafe { (buffer as *mut u8).add(offs) };
+            if mem_maps.is_mapped(page_addr & !0xfff) {
+                unsafe { core::ptr::copy_nonoverlapping(page_addr as *const u8, dst, page_len) };
+                result = efi::Status::SUCCESS;
+            } else {
+                unsafe { core::ptr::write_bytes(dst, 0, page_len) };
+            }
+        }
+        result
+    })
+}}
+
+#[cfg(feature = "writes")]
+eficall! {fn write_phys(
+    _this: *mut MemflowProtocol,
+    addr: u64,
+    buffer: *const c_void,
+    len: usize,
+) -> efi::Status {
+    if buffer.is_null() {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    access_phys(addr, len, || {
+        let mem_maps = unsafe { &EFI_MEM_MAPS };
+        if !pages(addr, len).all(|(page_addr, _, _)| mem_maps.is_mapped(page_addr & !0xfff)) {
+            return efi::Status::ACCESS_DENIED;
+        }
+        unsafe { core::ptr::copy_nonoverlapping(buffer as *const u8, addr as *mut u8, len) };
+        efi::Status::SUCCESS
+    })
+}}
+
+#[cfg(not(feature = "writes"))]
+eficall! {fn write_phys(
+    _this: *mut MemflowProtocol,
+    _addr: u64,
+    _buffer: *const c_void,
+    _len: usize,
+) -> efi::Status {
+    efi::Status::UNSUPPORTED
+}}
+
+eficall! {fn query_map(
+    _this: *mut MemflowProtocol,
+    addr: u64,
+    descriptor: *mut MemoryDescriptor,
+) -> efi::Status {
+    if descriptor.is_null() {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    let mem_maps = unsafe { &EFI_MEM_MAPS };
+    match mem_maps.descriptor(addr) {
+        Some(map) => {
+            unsafe { descriptor.write(*map) };
+            efi::Status::SUCCESS
+        }
+        None => efi::Status::NOT_FOUND,
+    }
+}}
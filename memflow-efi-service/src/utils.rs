@@ -1,10 +1,13 @@
 use core::{
+    cell::UnsafeCell,
     ffi::c_void,
+    hint::spin_loop,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use ::r_efi::system::{RuntimeSetVariable, TPL_HIGH_LEVEL};
+use memflow_efi_core::pe::{Export, ExportId, PeImage};
 
 use crate::{boot_services, error, system_table_mut};
 
@@ -71,68 +74,116 @@ pub fn hook_service_pointer(orig_func: *mut *mut c_void, hook_func: *mut c_void)
     orig_func_bak
 }
 
+/// Returns the address of the export `name` of the image mapped at `base`.
+///
+/// Forwarded exports are not followed, use `pe::PeImage` directly to inspect them.
 pub fn find_export(base: *const c_void, size: usize, name: &str) -> Option<usize> {
-    /*
-    let buf = unsafe { core::slice::from_raw_parts(base as *const _, size) };
-
-    use ::goblin::pe::{options::ParseOptions, PE};
-    let pe = PE::parse_with_opts(buf, &ParseOptions { resolve_rva: false }).ok()?;
-    let export = pe
-        .exports
-        .iter()
-        .find(|e| e.name.is_some() && e.name.unwrap() == name)?;
-    export.offset
-
-    //let pe = pelite::PeView::from_bytes(buf).ok()?;
-    */
-    None
+    let image = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+    match PeImage::parse(image)?.find_export(ExportId::Name(name))? {
+        Export::Rva(rva) => Some(base as usize + rva as usize),
+        Export::Forwarder(_) => None,
+    }
 }
 
+/// Ticket spinlock that keeps interrupts disabled on the owning cpu while it is held.
+///
+/// Cpus are served in the order they started waiting, so no cpu starves while others hammer the lock.
+/// Interrupts are disabled before a ticket is drawn, an interrupt handler on the same cpu
+/// therefore can never wait for a lock its own cpu already holds.
 pub struct Mutex<T> {
-    lock: AtomicBool,
-    inner: T,
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    inner: UnsafeCell<T>,
 }
 
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
 impl<T> Mutex<T> {
     pub const fn new(value: T) -> Self {
         Self {
-            lock: AtomicBool::new(false),
-            inner: value,
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            inner: UnsafeCell::new(value),
         }
     }
 
-    pub fn lock<'a>(&'a mut self) -> MutexGuard<'a, T> {
-        while self
-            .lock
-            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-            .is_err()
-        {
-            // TODO: yield thread?
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let interrupts = x86_64::instructions::interrupts::are_enabled();
+        x86_64::instructions::interrupts::disable();
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+
+        MutexGuard {
+            parent: self,
+            interrupts,
         }
-        MutexGuard { parent: self }
+    }
+
+    /// Takes the lock if it becomes free within `spins` attempts.
+    ///
+    /// No ticket is drawn unless the lock is free, so giving up never blocks later cpus.
+    pub fn try_lock_spin(&self, spins: usize) -> Option<MutexGuard<'_, T>> {
+        let interrupts = x86_64::instructions::interrupts::are_enabled();
+        x86_64::instructions::interrupts::disable();
+
+        for _ in 0..spins.max(1) {
+            let serving = self.now_serving.load(Ordering::Acquire);
+            if self
+                .next_ticket
+                .compare_exchange(serving, serving + 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Some(MutexGuard {
+                    parent: self,
+                    interrupts,
+                });
+            }
+            spin_loop();
+        }
+
+        if interrupts {
+            x86_64::instructions::interrupts::enable();
+        }
+        None
+    }
+
+    /// Releases the lock on behalf of a holder whose guard is never dropped, e.g. code aborted by a fault.
+    ///
+    /// The caller has to make sure the lock is held by such a holder, interrupts are left as they are.
+    pub unsafe fn force_unlock(&self) {
+        self.now_serving.fetch_add(1, Ordering::Release);
     }
 }
 
 pub struct MutexGuard<'a, T> {
-    parent: &'a mut Mutex<T>,
+    parent: &'a Mutex<T>,
+    // interrupts were enabled before the lock was taken
+    interrupts: bool,
 }
 
 impl<'a, T> Deref for MutexGuard<'a, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        &self.parent.inner
+        unsafe { &*self.parent.inner.get() }
     }
 }
 
 impl<'a, T> DerefMut for MutexGuard<'a, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.parent.inner
+        unsafe { &mut *self.parent.inner.get() }
     }
 }
 
 impl<'a, T> Drop for MutexGuard<'a, T> {
     fn drop(&mut self) {
-        self.parent.lock.store(false, Ordering::SeqCst);
+        self.parent.now_serving.fetch_add(1, Ordering::Release);
+        if self.interrupts {
+            x86_64::instructions::interrupts::enable();
+        }
     }
 }
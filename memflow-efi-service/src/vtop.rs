@@ -1,3 +1,5 @@
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
 // bit mask macros
 pub const fn make_bit_mask(a: u32, b: u32) -> u64 {
     (0xffff_ffff_ffff_ffff >> (63 - b)) & !(((1 as u64) << a) - 1)
@@ -49,6 +51,13 @@ macro_rules! check_entry {
 
 // TODO: write tests for these macros
 // pagetable indizes
+#[macro_export]
+macro_rules! pml5_index_bits {
+    ($a:expr) => {
+        ($a & make_bit_mask(48, 56)) >> 45
+    };
+}
+
 #[macro_export]
 macro_rules! pml4_index_bits {
     ($a:expr) => {
@@ -82,9 +91,36 @@ fn read_pt_address(addr: u64) -> u64 {
     unsafe { *(addr as *const u64) }
 }
 
+/// Returns the number of page table levels currently in use (4 or 5).
+///
+/// 5-level paging is enabled by the OS through CR4.LA57, so this has to be checked at runtime.
+pub fn paging_levels() -> usize {
+    if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+        5
+    } else {
+        4
+    }
+}
+
+/// Returns the physical address of the pml4 for the given virtual address.
+///
+/// With 4-level paging this is the dtb itself, with 5-level paging the pml5 entry is resolved.
+pub fn pml4_address(dtb: u64, addr: u64) -> Option<u64> {
+    if paging_levels() == 5 {
+        let pml5e = read_pt_address((dtb & make_bit_mask(12, 51)) | pml5_index_bits!(addr));
+        if !check_entry!(pml5e) {
+            return None;
+        }
+        Some(pml5e & make_bit_mask(12, 51))
+    } else {
+        Some(dtb & make_bit_mask(12, 51))
+    }
+}
+
 // TODO: return page size
 pub fn virt_to_phys(dtb: u64, addr: u64) -> Option<u64> {
-    let pml4e = read_pt_address((dtb & make_bit_mask(12, 51)) | pml4_index_bits!(addr));
+    let pml4 = pml4_address(dtb, addr)?;
+    let pml4e = read_pt_address(pml4 | pml4_index_bits!(addr));
     if !check_entry!(pml4e) {
         //return Err(Error::new("unable to read pml4e"));
         return None;
@@ -128,6 +164,103 @@ pub fn virt_to_phys(dtb: u64, addr: u64) -> Option<u64> {
     Some(phys_addr)
 }
 
+/// Effective permissions of a page reported by `walk_mapped_pages`, at their page table entry positions.
+pub const PAGE_WRITABLE: u64 = 1 << 1;
+pub const PAGE_USER: u64 = 1 << 2;
+pub const PAGE_NX: u64 = 1 << 63;
+
+struct PageWalker<F> {
+    f: F,
+    tables_left: usize,
+    // number of virtual address bits, 48 or 57
+    va_bits: u32,
+    stopped_at: Option<u64>,
+}
+
+impl<F: FnMut(u64, u64, u64, u64) -> bool> PageWalker<F> {
+    fn canonical(&self, addr: u64) -> u64 {
+        let shift = 64 - self.va_bits;
+        (((addr << shift) as i64) >> shift) as u64
+    }
+
+    // level 1 is a page table, 4 a pml4 and 5 a pml5
+    fn walk_table(&mut self, table: u64, level: u32, base: u64, start: u64, flags: u64) -> bool {
+        if self.tables_left == 0 {
+            self.stopped_at = Some(self.canonical(base.max(start)));
+            return false;
+        }
+        self.tables_left -= 1;
+
+        let shift = 12 + 9 * (level - 1);
+        let first = if start > base {
+            (start - base) >> shift
+        } else {
+            0
+        };
+
+        for i in first..512 {
+            let virt = base + (i << shift);
+            let entry = read_pt_address(table + i * 8);
+            // transition entries only exist at the leaf level, upper levels have to be present
+            let valid = if level == 1 {
+                check_entry!(entry)
+            } else {
+                get_bit!(entry, 0)
+            };
+            if !valid {
+                continue;
+            }
+
+            // writable and user have to be granted by all levels, nx by a single one
+            let flags = (flags & (entry | PAGE_NX)) | (entry & PAGE_NX);
+            if level == 1 || (level <= 3 && is_large_page!(entry)) {
+                let phys = entry & make_bit_mask(shift, 51);
+                let virt = self.canonical(virt);
+                if !(self.f)(virt, 1 << shift, phys, flags) {
+                    self.stopped_at = Some(virt);
+                    return false;
+                }
+            } else if !self.walk_table(entry & make_bit_mask(12, 51), level - 1, virt, start, flags) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Walks all mapped pages of the address space of `dtb` starting at the virtual address `start`.
+///
+/// `f(virt, size, phys, flags)` is called for every mapped page in ascending order,
+/// the walk stops as soon as it returns false. At most `max_tables` page tables are visited.
+///
+/// Returns the virtual address the walk stopped at or `None` if the end of the address space has been reached.
+pub fn walk_mapped_pages<F: FnMut(u64, u64, u64, u64) -> bool>(
+    dtb: u64,
+    start: u64,
+    max_tables: usize,
+    f: F,
+) -> Option<u64> {
+    let levels = paging_levels() as u32;
+    let va_bits = 12 + 9 * levels;
+    let mut walker = PageWalker {
+        f,
+        tables_left: max_tables,
+        va_bits,
+        stopped_at: None,
+    };
+
+    let start = start & make_bit_mask(0, va_bits - 1);
+    walker.walk_table(
+        dtb & make_bit_mask(12, 51),
+        levels,
+        0,
+        start,
+        PAGE_WRITABLE | PAGE_USER,
+    );
+    walker.stopped_at
+}
+
 fn as_page_aligned(val: u64) -> u64 {
     val - val % 0x1000
 }